@@ -0,0 +1,126 @@
+// State kept across resets in the STM32F4 backup SRAM, 4 KB from BKPSRAM_BASE. It needs no flash
+// driver and a write is a few hundred ns. The content survives a reset while VDD is up, and a power
+// loss only if VBAT is supplied: init() turns the backup regulator on for that case.
+// A single record lives there, trusted only if its magic, version and CRC match
+use core::mem::{size_of, MaybeUninit};
+use core::ptr;
+use embassy_stm32::pac;
+use crate::types::bms::{Peaks, Topology};
+
+const BKPSRAM_BASE: usize = 0x4002_4000;
+const RECORD_MAGIC: u32 = 0x424D_534C; // "BMSL"
+// Bump on any change to Persisted, an old record is then ignored instead of misread
+const RECORD_VERSION: u32 = 2;
+
+// What PrepareShutdown saves. SOC is not tracked by this firmware, so there is none to save.
+// Every record type here is laid out without padding, the whole slot is checksummed as bytes
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ShutdownRecord {
+    pub pec_errors: u32, // Counter::PecError at shutdown, since boot or the last clear
+    pub peaks: Peaks,
+    pub saved: u8,       // 1 once a PrepareShutdown wrote the fields above
+    _reserved: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Persisted {
+    pub shutdown: ShutdownRecord,
+    // Last SetTopology, kept as raw counts and checked again by topology() on load
+    cells: u8,
+    thermistors: u8,
+    _reserved: [u8; 2],
+}
+
+impl Persisted {
+    pub const fn new() -> Self {
+        Persisted {
+            shutdown: ShutdownRecord { pec_errors: 0, peaks: Peaks::new(), saved: 0, _reserved: [0; 3] },
+            cells: Topology::FULL.cells,
+            thermistors: Topology::FULL.thermistors,
+            _reserved: [0; 2],
+        }
+    }
+
+    // None if the saved counts don't fit this build's hardware
+    pub fn topology(&self) -> Option<Topology> {
+        Topology::new(self.cells, self.thermistors)
+    }
+
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.cells = topology.cells;
+        self.thermistors = topology.thermistors;
+    }
+}
+
+#[repr(C)]
+struct Slot {
+    magic: u32,
+    version: u32,
+    record: Persisted,
+    crc: u32, // CRC-32 of everything above
+}
+
+const _: () = assert!(size_of::<Slot>() <= 4096);
+const _: () = assert!(size_of::<ShutdownRecord>() == 4 + size_of::<Peaks>() + 4);
+const _: () = assert!(size_of::<Persisted>() == size_of::<ShutdownRecord>() + 4);
+const _: () = assert!(size_of::<Slot>() == 4 + 4 + size_of::<Persisted>() + 4);
+
+// Clocks the backup SRAM and lifts the backup domain write protection, before any load/store
+pub fn init() {
+    pac::RCC.apb1enr().modify(|w| w.set_pwren(true));
+    pac::PWR.cr1().modify(|w| w.set_dbp(true));
+    pac::RCC.ahb1enr().modify(|w| w.set_bkpsramen(true));
+    pac::PWR.csr1().modify(|w| w.set_bre(true));
+    // The regulator is ready in well under 1 ms, bounded so a board without VBAT can't hang here
+    for _ in 0..100_000 {
+        if pac::PWR.csr1().read().brr() {
+            break;
+        }
+    }
+}
+
+// The saved record, None after a power loss without VBAT, on a first boot or an old version
+pub fn load() -> Option<Persisted> {
+    let mut bytes = [0u8; size_of::<Slot>()];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        // SAFETY: inside the backup SRAM, clocked by init()
+        *byte = unsafe { ptr::read_volatile((BKPSRAM_BASE + i) as *const u8) };
+    }
+    let crc = u32::from_le_bytes(bytes[size_of::<Slot>() - 4..].try_into().ok()?);
+    if crc32(&bytes[..size_of::<Slot>() - 4]) != crc {
+        return None;
+    }
+    let mut slot = MaybeUninit::<Slot>::uninit();
+    // SAFETY: the CRC matched, so these bytes were written by store() from a valid Slot
+    let slot = unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), slot.as_mut_ptr() as *mut u8, bytes.len());
+        slot.assume_init()
+    };
+    (slot.magic == RECORD_MAGIC && slot.version == RECORD_VERSION).then_some(slot.record)
+}
+
+pub fn store(record: &Persisted) {
+    let mut slot = Slot { magic: RECORD_MAGIC, version: RECORD_VERSION, record: *record, crc: 0 };
+    let body = size_of::<Slot>() - 4;
+    // SAFETY: Slot is plain data without padding (checked above), viewed as bytes to checksum and copy it
+    slot.crc = crc32(unsafe { core::slice::from_raw_parts(&slot as *const Slot as *const u8, body) });
+    let bytes = unsafe { core::slice::from_raw_parts(&slot as *const Slot as *const u8, size_of::<Slot>()) };
+    for (i, &byte) in bytes.iter().enumerate() {
+        // SAFETY: inside the backup SRAM, clocked and unlocked by init()
+        unsafe { ptr::write_volatile((BKPSRAM_BASE + i) as *mut u8, byte) };
+    }
+}
+
+// CRC-32/ISO-HDLC, bitwise: the record is small and only checked at boot and shutdown
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
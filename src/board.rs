@@ -0,0 +1,29 @@
+// STM32 side of the LTC6811 wiring, the driver in ltc_management only knows embedded-hal traits
+use embassy_stm32::gpio::{Level, Output, Pin, Speed};
+use embassy_stm32::mode::Async;
+use embassy_stm32::spi::{BitOrder, Config, Instance, MisoPin, MosiPin, RxDma, SckPin, Spi, TxDma, MODE_3};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::Peripheral;
+use crate::ltc_management::{SpiDevice, LTC6811};
+
+pub type LtcSpi = SpiDevice<Spi<'static, Async>, Output<'static>>;
+pub type Ltc = LTC6811<Spi<'static, Async>, Output<'static>>;
+
+// SPI mode 3, MSB first, 1MHz with DMA on both directions. CS starts high, the LTC6811 deselected
+pub fn ltc_spi<T: Instance>(
+    peri: (impl Peripheral<P = T> + 'static),
+    sck: (impl Peripheral<P = impl SckPin<T>> + 'static),
+    mosi: (impl Peripheral<P = impl MosiPin<T>> + 'static),
+    miso: (impl Peripheral<P = impl MisoPin<T>> + 'static),
+    cs: (impl Peripheral<P = impl Pin> + 'static),
+    tx_dma: (impl Peripheral<P = impl TxDma<T>> + 'static),
+    rx_dma: (impl Peripheral<P = impl RxDma<T>> + 'static),
+) -> LtcSpi {
+    let mut spi_config = Config::default();
+    spi_config.mode = MODE_3;
+    spi_config.bit_order = BitOrder::MsbFirst;
+    spi_config.frequency = Hertz(1_000_000);
+
+    let spi = Spi::new(peri, sck, mosi, miso, tx_dma, rx_dma, spi_config);
+    SpiDevice::from_parts(spi, Output::new(cs, Level::High, Speed::VeryHigh))
+}
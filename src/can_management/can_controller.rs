@@ -1,40 +1,106 @@
 pub use super::CanFrame;
 
+use core::sync::atomic::{AtomicU8, Ordering};
+
 use embassy_stm32::bind_interrupts;
+use embassy_stm32::can::enums::{BusError, TryReadError};
 use embassy_stm32::can::filter::Mask32;
 use embassy_stm32::can::{
-    Can, Fifo, Rx0InterruptHandler, Rx1InterruptHandler, SceInterruptHandler, TxInterruptHandler
+    Can, Fifo, Mailbox, Rx0InterruptHandler, Rx1InterruptHandler, SceInterruptHandler, TxInterruptHandler
 };
+use embassy_stm32::interrupt::typelevel::{Handler, CAN1_TX, CAN2_TX};
+use embassy_stm32::pac;
 
 use embassy_stm32::peripherals::{CAN1, CAN2, PA11, PA12, PB12, PB13};
-use embassy_time::Duration;
+use embassy_time::{Duration, Timer};
+use crate::types::diagnostics::{Counter, DIAGNOSTICS};
 
 bind_interrupts!(struct Irqs1 {
     CAN1_RX0 => Rx0InterruptHandler<CAN1>;
     CAN1_RX1 => Rx1InterruptHandler<CAN1>;
     CAN1_SCE => SceInterruptHandler<CAN1>;
-    CAN1_TX => TxInterruptHandler<CAN1>;
+    CAN1_TX => TxStatusHandler, TxInterruptHandler<CAN1>;
 });
 
 bind_interrupts!(struct Irqs2 {
     CAN2_RX0 => Rx0InterruptHandler<CAN2>;
     CAN2_RX1 => Rx1InterruptHandler<CAN2>;
     CAN2_SCE => SceInterruptHandler<CAN2>;
-    CAN2_TX => TxInterruptHandler<CAN2>;
+    CAN2_TX => TxStatusHandler, TxInterruptHandler<CAN2>;
 });
 
+// Outcome of the last frame put in each TX mailbox, [CAN1, CAN2][mailbox], one of the TX_* below.
+// Filled by TxStatusHandler: embassy's TX handler clears TXOK/ALST/TERR together with RQCP,
+// so they have to be caught before it runs
+static TX_OUTCOME: [[AtomicU8; 3]; 2] = [
+    [AtomicU8::new(TX_PENDING), AtomicU8::new(TX_PENDING), AtomicU8::new(TX_PENDING)],
+    [AtomicU8::new(TX_PENDING), AtomicU8::new(TX_PENDING), AtomicU8::new(TX_PENDING)],
+];
+const TX_PENDING: u8 = 0;
+const TX_OK: u8 = 1;
+const TX_ARBITRATION_LOST: u8 = 2;
+const TX_ERROR: u8 = 3;
+
+// Bound before TxInterruptHandler on the TX interrupts, see TX_OUTCOME
+pub struct TxStatusHandler;
+
+impl TxStatusHandler {
+    fn record(regs: pac::can::Can, outcome: &[AtomicU8; 3]) {
+        let tsr = regs.tsr().read();
+        for (mailbox, slot) in outcome.iter().enumerate() {
+            if tsr.rqcp(mailbox) {
+                let result = if tsr.txok(mailbox) {
+                    TX_OK
+                } else if tsr.alst(mailbox) {
+                    TX_ARBITRATION_LOST
+                } else {
+                    TX_ERROR
+                };
+                slot.store(result, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Handler<CAN1_TX> for TxStatusHandler {
+    unsafe fn on_interrupt() {
+        Self::record(pac::CAN1, &TX_OUTCOME[0]);
+    }
+}
+
+impl Handler<CAN2_TX> for TxStatusHandler {
+    unsafe fn on_interrupt() {
+        Self::record(pac::CAN2, &TX_OUTCOME[1]);
+    }
+}
+
+// What happened to a frame after write() put it in a mailbox
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TxStatus {
+    Pending,         // still in the mailbox, not on the wire yet
+    Transmitted,     // acknowledged by at least one node
+    ArbitrationLost, // a higher priority frame won, automatic retransmission is off so it is gone
+    Error,           // bus error during transmission, e.g. no ACK
+}
+
 
 #[derive(Debug)]
 pub enum CanError {
-    NoItem,
+    NoItem,             // RX FIFOs empty, nothing wrong
+    Overrun,            // RX FIFO overflowed since the last read, frames were lost
+    BusError(BusError), // error reported by the controller
     Timeout,
-    WriteError,
+    WriteError,         // no TX mailbox took the frame
+    NotTransmitted,     // accepted into a mailbox but not acknowledged on the bus
+    ArbitrationLost,    // lost arbitration on every attempt, normal on a busy bus, not a fault
 }
 
 pub struct CanController<'a> {
     can: Can<'a>,
     tx_frame: Option<CanFrame>,
-    is_can2: bool
+    is_can2: bool,
+    rx_sequence: u32,
+    rx_overruns: u32
 }
 
 
@@ -60,7 +126,9 @@ impl<'a> CanController<'a>{
                 Irqs1
             ),
             tx_frame: None,
-            is_can2: false
+            is_can2: false,
+            rx_sequence: 0,
+            rx_overruns: 0
         };
         Self::new(controller, baudrate).await
     }
@@ -71,7 +139,9 @@ impl<'a> CanController<'a>{
         let controller = CanController {
             can: Can::new(peri, rx, tx, Irqs2),
             tx_frame: None,
-            is_can2: true
+            is_can2: true,
+            rx_sequence: 0,
+            rx_overruns: 0
         };
 
         can1.modify_filters().set_split(0).num_banks();
@@ -82,7 +152,36 @@ impl<'a> CanController<'a>{
         (Self::new(controller, baudrate).await, rx1, tx1)       
     }
 
-    pub async fn write(&mut self, frame: &CanFrame) -> Result<(), CanError> {
+    // Bench bring-up check: loops a known frame back through the controller and
+    // compares what comes out of the RX FIFO. The bus is left untouched
+    #[cfg_attr(not(feature = "can-self-test"), allow(unused))]
+    pub async fn self_test(&mut self) -> bool {
+        const SELF_TEST_ID: u16 = 0x7FF;
+        const SELF_TEST_DATA: [u8; 8] = [0x55, 0xAA, 0x55, 0xAA, 0x0F, 0xF0, 0x0F, 0xF0];
+
+        self.can.modify_config().set_loopback(true).set_silent(true);
+        while self.can.try_read().is_ok() {} // stale frames would fail the comparison
+
+        let frame = CanFrame::new(SELF_TEST_ID, &SELF_TEST_DATA);
+        let mut passed = false;
+        if self.can.try_write(&frame.frame()).is_ok() {
+            for _ in 0..10 {
+                Timer::after_millis(1).await;
+                if let Ok(envelope) = self.can.try_read() {
+                    let echo = CanFrame::from_envelope(envelope, 0);
+                    passed = echo.id() == SELF_TEST_ID && echo.payload() == SELF_TEST_DATA;
+                    break;
+                }
+            }
+        }
+
+        self.can.modify_config().set_loopback(false).set_silent(false);
+        passed
+    }
+
+    // Ok means the frame is in a TX mailbox, not that it went out: see tx_status and write_confirmed.
+    // A frame that then loses arbitration is gone, periodic ones simply go out again next period
+    pub async fn write(&mut self, frame: &CanFrame) -> Result<Mailbox, CanError> {
         let mut attempts: u8 = 0;
 
         while (self.tx_frame.is_some()) && (attempts < 5) {
@@ -91,6 +190,7 @@ impl<'a> CanController<'a>{
         }
 
         if attempts >= 5 {
+            DIAGNOSTICS.bump(Counter::CanTxDrop);
             return Err(CanError::Timeout)
         }
 
@@ -100,12 +200,21 @@ impl<'a> CanController<'a>{
 
         attempts = 0;
 
+        // Whichever mailbox gets the frame is empty now, forget the outcome of its previous frame
+        let regs = self.regs();
+        let tsr = regs.tsr().read();
+        for (mailbox, slot) in self.tx_outcome().iter().enumerate() {
+            if tsr.tme(mailbox) {
+                slot.store(TX_PENDING, Ordering::Relaxed);
+            }
+        }
+
         while attempts < 4 {
             if let Some(ref tx_frame) = self.tx_frame {
                 match self.can.try_write(&tx_frame.frame()) {
-                    Ok(_) => {
+                    Ok(status) => {
                         self.tx_frame = None;
-                        return Ok(())
+                        return Ok(status.mailbox())
                     }
                     Err(_) => {
                         attempts = attempts.wrapping_add(1);
@@ -114,20 +223,112 @@ impl<'a> CanController<'a>{
             }
         }
         self.tx_frame = None;
+        DIAGNOSTICS.bump(Counter::CanTxDrop);
         Err(CanError::WriteError)
     } 
 
+    // Write and wait until the frame left its mailbox, Ok only if it was acknowledged on the bus.
+    // A frame takes well under 1ms at 500kbit/s, TX_CONFIRM_TIMEOUT_US covers a busy bus.
+    // Automatic retransmission is off, so a frame beaten by higher priority traffic is written
+    // again here, up to TX_ARBITRATION_RETRIES times, without logging or counting anything
+    pub async fn write_confirmed(&mut self, frame: &CanFrame) -> Result<(), CanError> {
+        const TX_CONFIRM_TIMEOUT_US: u64 = 5_000;
+        const TX_CONFIRM_POLL_US: u64 = 100;
+        const TX_ARBITRATION_RETRIES: u8 = 3;
+
+        let mut retries: u8 = 0;
+        loop {
+            let mailbox = self.write(frame).await?;
+            let mut status = self.tx_status(mailbox);
+            for _ in 0..TX_CONFIRM_TIMEOUT_US / TX_CONFIRM_POLL_US {
+                if status != TxStatus::Pending {
+                    break;
+                }
+                Timer::after_micros(TX_CONFIRM_POLL_US).await;
+                status = self.tx_status(mailbox);
+            }
+
+            match status {
+                TxStatus::Transmitted => return Ok(()),
+                TxStatus::ArbitrationLost if retries < TX_ARBITRATION_RETRIES => retries += 1,
+                TxStatus::ArbitrationLost => {
+                    DIAGNOSTICS.bump(Counter::CanTxDrop);
+                    return Err(CanError::ArbitrationLost)
+                }
+                TxStatus::Error => {
+                    DIAGNOSTICS.bump(Counter::CanTxDrop);
+                    return Err(CanError::NotTransmitted)
+                }
+                // Still pending, it may go out later: not counted as dropped
+                TxStatus::Pending => return Err(CanError::Timeout),
+            }
+        }
+    }
+
+    // Status of the last frame written to `mailbox`
+    pub fn tx_status(&self, mailbox: Mailbox) -> TxStatus {
+        let index = mailbox as usize;
+        if !self.regs().tsr().read().tme(index) {
+            return TxStatus::Pending;
+        }
+        match self.tx_outcome()[index].load(Ordering::Relaxed) {
+            TX_OK => TxStatus::Transmitted,
+            TX_ARBITRATION_LOST => TxStatus::ArbitrationLost,
+            TX_ERROR => TxStatus::Error,
+            _ => TxStatus::Pending, // empty, the interrupt has not run yet
+        }
+    }
+
+    fn regs(&self) -> pac::can::Can {
+        if self.is_can2 { pac::CAN2 } else { pac::CAN1 }
+    }
+
+    fn tx_outcome(&self) -> &'static [AtomicU8; 3] {
+        &TX_OUTCOME[self.is_can2 as usize]
+    }
+
     pub async fn read(&mut self) -> Result<CanFrame, CanError> {
-        let envelope = self.can.try_read();
-        match envelope {
-            Ok(_) => {
-                let frame = CanFrame::from_envelope(envelope.unwrap());
-                return Ok(frame);        
+        match self.can.try_read() {
+            Ok(envelope) => {
+                self.rx_sequence = self.rx_sequence.wrapping_add(1);
+                Ok(CanFrame::from_envelope(envelope, self.rx_sequence))
             }
 
-            Err(_) => {
-                return Err(CanError::NoItem);
+            Err(TryReadError::Empty) => {
+                if self.check_overrun() {
+                    Err(CanError::Overrun)
+                } else {
+                    Err(CanError::NoItem)
+                }
+            }
+
+            Err(TryReadError::BusError(e)) => Err(CanError::BusError(e)),
+        }
+    }
+
+    // embassy does not report FIFO overruns, so look at the FOVR flags directly and clear them
+    fn check_overrun(&mut self) -> bool {
+        let regs = self.regs();
+        let mut overrun = false;
+
+        for fifo in 0..2 {
+            if regs.rfr(fifo).read().fovr() {
+                regs.rfr(fifo).write(|w| w.set_fovr(true)); // rc_w1
+                self.rx_overruns = self.rx_overruns.wrapping_add(1);
+                DIAGNOSTICS.bump(Counter::CanRxOverrun);
+                overrun = true;
             }
         }
+        overrun
+    }
+
+    // Number of frames received so far, the last one carries this value as its sequence
+    pub fn _rx_sequence(&self) -> u32 {
+        self.rx_sequence
     }
-}
\ No newline at end of file
+
+    // Number of RX FIFO overruns seen since boot
+    pub fn rx_overruns(&self) -> u32 {
+        self.rx_overruns
+    }
+}
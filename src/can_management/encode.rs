@@ -0,0 +1,303 @@
+// Byte layouts of every frame we transmit, in one place so they can be checked against the DBC.
+// All multi-byte fields are little endian. The functions only build payloads, sending and the
+// rolling counters stay in can_management.
+use crate::get_byte;
+use crate::types::bms::{BmsSnapshot, NUM_CELLS, NUM_HISTORY};
+use crate::types::{DeciCelsius, DeciMilliVolt};
+use crate::types::fault::Severity;
+use crate::types::diagnostics::Counter;
+use crate::types::scaling::{cell_delta_to_can, current_to_can, pack_diff_to_can, pack_to_can};
+use crate::ltc_management::ltc6811::{BalancingState, DiagnosticReport, UpdateTiming};
+
+// VoltageId: 0-1 max cell (0.1 mV) | 2-3 min cell (0.1 mV) | 4-5 avg cell (0.1 mV) | 6 counter | 7 CRC
+// Byte 0-5 as before the counter and CRC were added, the cell sum is in PackVoltage
+pub fn encode_voltage_frame(bms: &BmsSnapshot, counter: u8) -> [u8; 8] {
+    seal([
+        get_byte!(bms.max_volt.into_raw(), 0),
+        get_byte!(bms.max_volt.into_raw(), 1),
+        get_byte!(bms.min_volt.into_raw(), 0),
+        get_byte!(bms.min_volt.into_raw(), 1),
+        get_byte!(bms.avg_volt.into_raw(), 0),
+        get_byte!(bms.avg_volt.into_raw(), 1),
+        counter & 0x0F,
+        0,
+    ])
+}
+
+// TemperatureId: 0-1 max temp (0.1 °C) | 2-3 min temp (0.1 °C) | 4-5 current (0.1 A, i16, + = discharge) | 6 counter | 7 CRC
+pub fn encode_temperature_frame(bms: &BmsSnapshot, counter: u8) -> [u8; 8] {
+    let current = current_to_can(bms.current);
+    seal([
+        get_byte!(bms.max_temp.into_raw(), 0),
+        get_byte!(bms.max_temp.into_raw(), 1),
+        get_byte!(bms.min_temp.into_raw(), 0),
+        get_byte!(bms.min_temp.into_raw(), 1),
+        get_byte!(current, 0),
+        get_byte!(current, 1),
+        counter & 0x0F,
+        0,
+    ])
+}
+
+// AvgTemperatureId: 0-1 avg temp (0.1 °C) | 2-5 reserved, 0 | 6 counter | 7 CRC
+pub fn encode_avg_temperature_frame(bms: &BmsSnapshot, counter: u8) -> [u8; 8] {
+    seal([
+        get_byte!(bms.avg_temp.into_raw(), 0),
+        get_byte!(bms.avg_temp.into_raw(), 1),
+        0,
+        0,
+        0,
+        0,
+        counter & 0x0F,
+        0,
+    ])
+}
+
+// PackVoltage: 0-1 sensed pack (10 mV, 0 = no reading) | 2-3 cell sum (10 mV) | 4-5 sensed - sum (10 mV, i16) | 6 counter | 7 CRC
+pub fn encode_pack_voltage_frame(bms: &BmsSnapshot, counter: u8) -> [u8; 8] {
+    let sum = bms.pack_voltage_10mv();
+    let (sensed, diff) = match bms.pack_volt {
+        Some(pack_volt) => (pack_to_can(pack_volt), pack_diff_to_can(pack_volt, bms.tot_volt)),
+        None => (0, 0),
+    };
+    seal([
+        get_byte!(sensed, 0),
+        get_byte!(sensed, 1),
+        get_byte!(sum, 0),
+        get_byte!(sum, 1),
+        get_byte!(diff, 0),
+        get_byte!(diff, 1),
+        counter & 0x0F,
+        0,
+    ])
+}
+
+// PackSummary, the whole pack health in one frame for the dashboard:
+// 0-1 min cell (0.1 mV) | 2-3 max cell (0.1 mV) | 4 max - min (10 mV, saturating at 255)
+// 5 min-cell index in the low nibble, max-cell index in the high nibble | 6 fault summary | 7 reserved, 0
+pub fn encode_pack_summary_frame(bms: &BmsSnapshot, fault_summary: u8) -> [u8; 8] {
+    let delta = cell_delta_to_can(bms.max_volt.saturating_sub(bms.min_volt));
+    [
+        get_byte!(bms.min_volt.into_raw(), 0),
+        get_byte!(bms.min_volt.into_raw(), 1),
+        get_byte!(bms.max_volt.into_raw(), 0),
+        get_byte!(bms.max_volt.into_raw(), 1),
+        delta,
+        (bms.min_cell & 0x0F) | ((bms.max_cell & 0x0F) << 4),
+        fault_summary,
+        0,
+    ]
+}
+
+// Tech1..Tech3: four cells each (0.1 mV), frame n carries cells 4n..4n+3
+pub fn encode_tech_cells_frame(bms: &BmsSnapshot, frame: usize) -> [u8; 8] {
+    let first = frame * 4;
+    [
+        get_byte!(bms.cell_volts[first].into_raw(), 0),
+        get_byte!(bms.cell_volts[first].into_raw(), 1),
+        get_byte!(bms.cell_volts[first + 1].into_raw(), 0),
+        get_byte!(bms.cell_volts[first + 1].into_raw(), 1),
+        get_byte!(bms.cell_volts[first + 2].into_raw(), 0),
+        get_byte!(bms.cell_volts[first + 2].into_raw(), 1),
+        get_byte!(bms.cell_volts[first + 3].into_raw(), 0),
+        get_byte!(bms.cell_volts[first + 3].into_raw(), 1),
+    ]
+}
+
+// TechDelta: all 12 cells in one frame instead of Tech1..Tech3, for the high rate tech stream.
+// Byte 0-1: bits 0-12 reference = min cell (mV), bits 13-15 step exponent e, step = 2^e mV
+// Byte 2-7: 4 bit delta of cell i above the reference in steps, byte 2 + i/2, even i in the low nibble
+// The encoder picks the smallest step that fits the spread in 15 steps, so cell = ref + delta * step
+// is within step/2 of the real value; deltas saturate at 15 past 128 mV * 15 of spread
+pub fn encode_tech_delta_frame(bms: &BmsSnapshot) -> [u8; 8] {
+    let cells_mv = bms.cell_volts.map(|cell| cell.millivolts() as u32);
+    let reference = cells_mv.iter().copied().min().unwrap_or(0).min(0x1FFF);
+    let spread = cells_mv.iter().map(|&cell| cell.saturating_sub(reference)).max().unwrap_or(0);
+    let mut exp: u32 = 0;
+    while exp < 7 && spread > (15 << exp) {
+        exp += 1;
+    }
+
+    let header = (reference as u16) | ((exp as u16) << 13);
+    let mut payload = [get_byte!(header, 0), get_byte!(header, 1), 0, 0, 0, 0, 0, 0];
+    for (i, &cell) in cells_mv.iter().enumerate() {
+        let step = 1u32 << exp;
+        let delta = ((cell.saturating_sub(reference) + step / 2) >> exp).min(15) as u8;
+        payload[2 + i / 2] |= delta << ((i % 2) * 4);
+    }
+    payload
+}
+
+// Inverse of encode_tech_delta_frame, cells back in 0.1 mV. For replay and bench tooling
+#[allow(unused)]
+pub fn decode_tech_delta_frame(payload: &[u8; 8]) -> [u16; NUM_CELLS] {
+    let header = u16::from_le_bytes([payload[0], payload[1]]);
+    let reference = header & 0x1FFF;
+    let exp = header >> 13;
+    let mut cells = [0u16; NUM_CELLS];
+    for (i, cell) in cells.iter_mut().enumerate() {
+        let delta = ((payload[2 + i / 2] >> ((i % 2) * 4)) & 0x0F) as u16;
+        *cell = (reference + (delta << exp)) * 10;
+    }
+    cells
+}
+
+// Tech4 and ThermistorDetail: the four thermistors (0.1 °C), GPIO1 first
+pub fn encode_tech_temps_frame(bms: &BmsSnapshot) -> [u8; 8] {
+    [
+        get_byte!(bms.temps[0].into_raw(), 0),
+        get_byte!(bms.temps[0].into_raw(), 1),
+        get_byte!(bms.temps[1].into_raw(), 0),
+        get_byte!(bms.temps[1].into_raw(), 1),
+        get_byte!(bms.temps[2].into_raw(), 0),
+        get_byte!(bms.temps[2].into_raw(), 1),
+        get_byte!(bms.temps[3].into_raw(), 0),
+        get_byte!(bms.temps[3].into_raw(), 1),
+    ]
+}
+
+// CellHistory, answer to RequestCellHistory, the history is split over frames of 3 samples:
+// Byte 0: cell index | Byte 1: frame number n | Byte 2-7: samples 3n..3n+2 (0.1 mV), oldest first, 0 past the end
+pub fn encode_cell_history_frame(cell: u8, frame: usize, history: &[u16; NUM_HISTORY]) -> [u8; 8] {
+    let mut payload = [cell, frame as u8, 0, 0, 0, 0, 0, 0];
+    for n in 0..3 {
+        if let Some(&sample) = history.get(frame * 3 + n) {
+            payload[2 + n * 2] = get_byte!(sample, 0);
+            payload[3 + n * 2] = get_byte!(sample, 1);
+        }
+    }
+    payload
+}
+
+// DiagnosticCounters, answer to GetDiagnostics, one frame per counter in Counter order:
+// Byte 0: counter index | Byte 1: number of counters | Byte 2-5: count (u32)
+pub fn encode_diagnostic_counter_frame(index: u8, count: u32) -> [u8; 6] {
+    [
+        index,
+        Counter::COUNT as u8,
+        get_byte!(count, 0),
+        get_byte!(count, 1),
+        get_byte!(count, 2),
+        get_byte!(count, 3),
+    ]
+}
+
+// BalanceStatus:
+// Byte 0: bit0 balancing active, bit1 dry run
+// Byte 1-2: discharge bitmap written to the LTC6811, bit n = cell n+1
+// Byte 3-4: discharge bitmap the balancing logic asked for (differs from the above only in dry run)
+// Byte 5: BalancingState, 0 inactive, 1 active, 2 waiting for the charge window,
+//         3 stopped balanced, 4 stopped by a fault, 5 stopped on ForceBalance timeout
+pub fn encode_balance_status_frame(active: bool, dry_run: bool, discharge_bitmap: u16, planned_bitmap: u16, state: BalancingState) -> [u8; 6] {
+    [
+        (active as u8) | ((dry_run as u8) << 1),
+        get_byte!(discharge_bitmap, 0),
+        get_byte!(discharge_bitmap, 1),
+        get_byte!(planned_bitmap, 0),
+        get_byte!(planned_bitmap, 1),
+        state as u8,
+    ]
+}
+
+// SelfTestResult:
+// Byte 0: pass flags (bit0 CVST, bit1 AXST, bit2 open wire, bit3 config readback, bit4 status B, bit7 all passed)
+// Byte 1-2: open wire bitmap C0..C12
+pub fn encode_diagnostics_frame(report: &DiagnosticReport) -> [u8; 3] {
+    let flags: u8 = (report.cell_test as u8)
+        | ((report.aux_test as u8) << 1)
+        | ((report.open_wire_test as u8) << 2)
+        | ((report.config_test as u8) << 3)
+        | ((report.status_test as u8) << 4)
+        | ((report.passed() as u8) << 7);
+
+    [
+        flags,
+        get_byte!(report.open_wire, 0),
+        get_byte!(report.open_wire, 1),
+    ]
+}
+
+// ErrorId:
+// Byte 0: bit0 any fault active, bit1 measurements stale (no LTC update for MEASUREMENT_STALE_MS),
+//         the other data frames stop until updates resume. 0 = all clear
+// Byte 1-2: fault bitfield, see the FAULT_* bits in types::fault
+// Byte 3: warning bitfield, see the WARN_* bits in main. Warnings never open the loop
+// Byte 4: highest Severity among the active faults (1 warning, 2 derate, 3 critical), 0 = all clear
+pub fn encode_fault_status_frame(faults: u16, warnings: u8, severity: Option<Severity>, stale: bool) -> [u8; 5] {
+    [
+        (faults != 0) as u8 | ((stale as u8) << 1),
+        get_byte!(faults, 0),
+        get_byte!(faults, 1),
+        warnings,
+        severity.map_or(0, |severity| severity as u8),
+    ]
+}
+
+// UpdateTiming: bytes 0-1 last, 2-3 average, 4-5 max LTC update duration, in 0.1ms (saturating)
+pub fn encode_update_timing_frame(timing: &UpdateTiming) -> [u8; 6] {
+    let last = (timing.last_us / 100).min(u16::MAX as u32) as u16;
+    let avg = (timing.avg_us / 100).min(u16::MAX as u32) as u16;
+    let max = (timing.max_us / 100).min(u16::MAX as u32) as u16;
+    [
+        get_byte!(last, 0),
+        get_byte!(last, 1),
+        get_byte!(avg, 0),
+        get_byte!(avg, 1),
+        get_byte!(max, 0),
+        get_byte!(max, 1),
+    ]
+}
+
+// Limits in force when GetThresholds arrived
+#[derive(Debug, Copy, Clone)]
+pub struct Thresholds {
+    pub min_volt: DeciMilliVolt,
+    pub max_volt: DeciMilliVolt,
+    pub pack_floor: u32, // 100uV, like tot_volt
+    pub min_temp: DeciCelsius,
+    pub max_temp: DeciCelsius,
+    pub bal_epsilon: i16, // 0.1 mV, the one in use at the current max_temp
+    pub from_command: u8, // THRESHOLD_* bits of the values set over CAN, 0 = all defaults
+}
+
+// Thresholds::from_command bits
+pub const THRESHOLD_PACK_FLOOR: u8 = 1 << 0; // SetPackMinVoltage
+
+// Thresholds, answer to GetThresholds, two frames told apart by byte 0:
+// page 0: 0 page | 1 from_command | 2-3 min cell (0.1 mV) | 4-5 max cell (0.1 mV) | 6-7 pack floor (10 mV)
+// page 1: 0 page | 1 from_command | 2-3 min temp (0.1 °C) | 4-5 max temp (0.1 °C) | 6-7 balancing epsilon (0.1 mV, i16)
+pub fn encode_thresholds_frame(thresholds: &Thresholds, page: u8) -> [u8; 8] {
+    let (a, b, c) = if page == 0 {
+        (thresholds.min_volt.into_raw(), thresholds.max_volt.into_raw(), pack_to_can(thresholds.pack_floor))
+    } else {
+        (thresholds.min_temp.into_raw(), thresholds.max_temp.into_raw(), thresholds.bal_epsilon as u16)
+    };
+    [
+        page,
+        thresholds.from_command,
+        get_byte!(a, 0),
+        get_byte!(a, 1),
+        get_byte!(b, 0),
+        get_byte!(b, 1),
+        get_byte!(c, 0),
+        get_byte!(c, 1),
+    ]
+}
+
+// Fill byte 7 with the CRC of bytes 0..=6
+fn seal(mut payload: [u8; 8]) -> [u8; 8] {
+    payload[7] = crc8(&payload[..7]);
+    payload
+}
+
+// CRC-8/SAE-J1850: poly 0x1D, init 0xFF, final xor 0xFF
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xFF;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x1D } else { crc << 1 };
+        }
+    }
+    crc ^ 0xFF
+}
@@ -1,34 +1,40 @@
 use embassy_stm32::can::{frame::Envelope, Frame, Id, StandardId};
+use embassy_time::Instant;
 
 #[derive(Clone)]
 pub struct CanFrame {
     id: u16,
     data: [u8; 8],
-    _len: usize,
-    frame: Frame
+    len: usize,         // DLC, bytes past it in `data` are padding
+    frame: Frame,
+    timestamp: Instant, // reception time for received frames, creation time otherwise
+    sequence: u32       // RX sequence number, 0 for frames built locally
 }
 
 impl CanFrame {
+    // Payloads past 8 bytes are cut to 8, the DLC and the frame sent both follow the cut data
     pub fn new(id: u16, data: &[u8]) -> Self {
         let mut frame_data = [0u8; 8]; 
-        let _len = data.len().min(8);
+        let len = data.len().min(8);
 
-        frame_data[.._len].copy_from_slice(&data[.._len]);
+        frame_data[..len].copy_from_slice(&data[..len]);
 
         let tx_frame = Frame::new_data(
             StandardId::new(id as _).unwrap(),
-            data,
+            &frame_data[..len],
         ).unwrap();
 
         CanFrame {
             id,
             data: frame_data,
-            _len,
-            frame: tx_frame
+            len,
+            frame: tx_frame,
+            timestamp: Instant::now(),
+            sequence: 0
         }
     }
 
-    pub fn from_envelope(envelope: Envelope) -> Self {
+    pub fn from_envelope(envelope: Envelope, sequence: u32) -> Self {
         let rx_frame = envelope.frame;
         let mut frame_data = [0u8; 8]; 
         let len: usize = rx_frame.header().len().min(8) as usize;
@@ -43,8 +49,10 @@ impl CanFrame {
         CanFrame {
             id,
             data: frame_data,
-            _len: rx_frame.header().len() as usize,
-            frame: rx_frame
+            len,
+            frame: rx_frame,
+            timestamp: envelope.ts,
+            sequence
         }
     }
 
@@ -52,10 +60,15 @@ impl CanFrame {
         self.frame
     }
 
-    pub fn bytes(&self) -> [u8; 8] {
+    pub fn _bytes(&self) -> [u8; 8] {
         self.data
     }
 
+    // Only the bytes actually transmitted, as given by the DLC
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
     pub fn _byte(&self, index: usize) -> u8 {
         self.data[index]
     }
@@ -64,7 +77,40 @@ impl CanFrame {
         self.id
     }
 
-    pub fn _len(&self) -> usize {
-        self._len
+    // DLC, 0..=8
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn timestamp(&self) -> Instant {
+        self.timestamp
+    }
+
+    pub fn sequence(&self) -> u32 {
+        self.sequence
     }
-}
\ No newline at end of file
+}
+// Layout shared by the on/off commands (Balancing, Tech), so they can grow without new IDs:
+// Byte 0: enable, anything but 0 turns the function on
+// Byte 1: mode, meaning per command, 0 = the default behaviour
+// Byte 2-3: parameter, little endian, meaning per mode, 0 when unused
+// Missing bytes read as 0, so the original 1 byte command keeps working unchanged
+#[derive(Debug, Copy, Clone, Eq, PartialEq, defmt::Format)]
+pub struct Command {
+    pub enable: bool,
+    pub mode: u8,
+    pub param: u16,
+}
+
+impl Command {
+    // None for an empty payload, there is no enable byte to act on
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let byte = |i: usize| payload.get(i).copied().unwrap_or(0);
+        let &enable = payload.first()?;
+        Some(Command {
+            enable: enable != 0,
+            mode: byte(1),
+            param: u16::from_le_bytes([byte(2), byte(3)]),
+        })
+    }
+}
@@ -1,10 +1,19 @@
 pub mod can_controller;
 pub mod frame;
-use crate::types::SLAVEBMS;
+pub mod encode;
+use crate::types::bms::{BmsSnapshot, NUM_HISTORY};
 use crate::CanMsg;
+use crate::types::fault::Severity;
+use crate::types::diagnostics::Counter;
+use crate::ltc_management::ltc6811::{BalancingState, DiagnosticReport, UpdateTiming};
 pub use can_controller::CanController;
 pub use can_controller::CanError;
-pub use frame::CanFrame;
+#[allow(unused)]
+pub use can_controller::TxStatus;
+pub use frame::{CanFrame, Command};
+pub use encode::{Thresholds, THRESHOLD_PACK_FLOOR};
+use encode::*;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 #[macro_export]
 macro_rules! get_byte {
@@ -17,175 +26,191 @@ macro_rules! get_byte {
     };
 }
 
-pub async fn can_operation(bms: &SLAVEBMS, can: &mut CanController<'_>) -> Result<(), CanError>{
-    let tot_v = (bms.tot_volt()/100) as u16;
-    static mut TEMP: usize = 0 as usize;
-    unsafe {
-        let can_first: [u8; 8] = [
-            get_byte!(bms.max_volt(), 0),
-            get_byte!(bms.max_volt(), 1),
-            get_byte!(bms.min_volt(), 0),
-            get_byte!(bms.min_volt(), 1),
-            get_byte!(bms.avg_volt(), 0),
-            get_byte!(bms.avg_volt(), 1),
-            get_byte!(tot_v, 0),
-            get_byte!(tot_v, 1),
-        ];
-        TEMP = TEMP.wrapping_add(1);
-        if TEMP == (12 as usize) {
-            TEMP = 0 as usize;
-        }
-        let frame_send = CanFrame::new(CanMsg::VoltageId.as_raw(), &can_first);
-        match can.write(&frame_send).await {
-            Ok(_) => {}
-
-            Err(CanError::Timeout) => {
-                //info!("Timeout Can connection");
-                return Err(CanError::Timeout);
-            }
-
-            Err(_) => {
-                //info!("Can write error");
-                return Err(CanError::WriteError);
-            }
-        }
-    }
-
-    let can_second = [
-        get_byte!(bms.max_temp(), 0),
-        get_byte!(bms.max_temp(), 1),
-        get_byte!(bms.min_temp(), 0),
-        get_byte!(bms.min_temp(), 1),
-        get_byte!(bms.current(), 0),
-        get_byte!(bms.current(), 1),
-        get_byte!(bms.current(), 2),
-        get_byte!(bms.current(), 3)
-    ];
+// Rolling counters of the sealed frames, one per message so the VCU can check continuity
+static VOLTAGE_COUNTER: AtomicU8 = AtomicU8::new(0);
+static TEMPERATURE_COUNTER: AtomicU8 = AtomicU8::new(0);
+static AVG_TEMPERATURE_COUNTER: AtomicU8 = AtomicU8::new(0);
+static PACK_VOLTAGE_COUNTER: AtomicU8 = AtomicU8::new(0);
+
+// Spacing between the frames of the tech set, lets the three TX mailboxes drain
+const TECH_FRAME_GAP_MS: u64 = 5;
+
+// Builds and sends the normal mode frame selected by `msg`, layouts are in `encode`.
+// Each sealed frame carries a 4 bit rolling counter and a CRC-8/SAE-J1850 in bytes 6-7,
+// ThermistorDetail is not sealed, the four readings take all 8 bytes
+pub async fn can_operation(bms: &BmsSnapshot, can: &mut CanController<'_>, msg: CanMsg) -> Result<(), CanError>{
+    let payload: [u8; 8] = match msg {
+        CanMsg::VoltageId => encode_voltage_frame(bms, VOLTAGE_COUNTER.fetch_add(1, Ordering::Relaxed)),
+        CanMsg::TemperatureId => encode_temperature_frame(bms, TEMPERATURE_COUNTER.fetch_add(1, Ordering::Relaxed)),
+        CanMsg::AvgTemperatureId => encode_avg_temperature_frame(bms, AVG_TEMPERATURE_COUNTER.fetch_add(1, Ordering::Relaxed)),
+        CanMsg::PackVoltage => encode_pack_voltage_frame(bms, PACK_VOLTAGE_COUNTER.fetch_add(1, Ordering::Relaxed)),
+        CanMsg::ThermistorDetail => encode_tech_temps_frame(bms),
+        _ => return Ok(()),
+    };
 
-    let frame_send = CanFrame::new(CanMsg::TemperatureId.as_raw(), &can_second);
+    let frame_send = CanFrame::new(msg.as_raw(), &payload);
     match can.write(&frame_send).await {
         Ok(_) => Ok(()),
 
         Err(CanError::Timeout) => {
             //info!("Timeout Can connection");
-            return Err(CanError::Timeout);
+            Err(CanError::Timeout)
         }
 
         Err(_) => {
             //info!("Can write error");
-            return Err(CanError::WriteError);
+            Err(CanError::WriteError)
         }
     }
 }
 
-
-
-pub async fn can_operation_tech(bms: &SLAVEBMS, can: &mut CanController<'_>) -> Result<(), CanError>{
-    let can_first: [u8; 8] = [
-        get_byte!(bms.cell_volts(0), 0),
-        get_byte!(bms.cell_volts(0), 1),
-        get_byte!(bms.cell_volts(1), 0),
-        get_byte!(bms.cell_volts(1), 1),
-        get_byte!(bms.cell_volts(2), 0),
-        get_byte!(bms.cell_volts(2), 1),
-        get_byte!(bms.cell_volts(3), 0),
-        get_byte!(bms.cell_volts(3), 1)
+// Tech1..Tech4, best effort: every frame is attempted even if an earlier one failed.
+// On failure the error carries which ones did not go out, bit n = Tech(n+1)
+pub async fn can_operation_tech(bms: &BmsSnapshot, can: &mut CanController<'_>) -> Result<(), u8>{
+    let frames = [
+        (CanMsg::Tech1, encode_tech_cells_frame(bms, 0)),
+        (CanMsg::Tech2, encode_tech_cells_frame(bms, 1)),
+        (CanMsg::Tech3, encode_tech_cells_frame(bms, 2)),
+        (CanMsg::Tech4, encode_tech_temps_frame(bms)),
     ];
-    let frame_send = CanFrame::new(CanMsg::Tech1.as_raw(), &can_first);
-    match can.write(&frame_send).await {
-        Ok(_) => {}
 
-        Err(CanError::Timeout) => {
-            //info!("Timeout Can connection");
-            return Err(CanError::Timeout);
+    let mut failed: u8 = 0;
+    for (i, (msg, payload)) in frames.iter().enumerate() {
+        if i > 0 {
+            embassy_time::Timer::after_millis(TECH_FRAME_GAP_MS).await;
         }
-
-        Err(_) => {
-            //info!("Can write tech error");
-            return Err(CanError::WriteError);
+        let frame_send = CanFrame::new(msg.as_raw(), payload);
+        if can.write(&frame_send).await.is_err() {
+            failed |= 1 << i;
         }
     }
 
-    let can_second = [
-        get_byte!(bms.cell_volts(4), 0),
-        get_byte!(bms.cell_volts(4), 1),
-        get_byte!(bms.cell_volts(5), 0),
-        get_byte!(bms.cell_volts(5), 1),
-        get_byte!(bms.cell_volts(6), 0),
-        get_byte!(bms.cell_volts(6), 1),
-        get_byte!(bms.cell_volts(7), 0),
-        get_byte!(bms.cell_volts(7), 1)
-    ];
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(failed)
+    }
+}
 
-    let frame_send = CanFrame::new(CanMsg::Tech2.as_raw(), &can_second);
+// Compact tech set: every cell in one TechDelta frame, then Tech4 with the temperatures
+pub async fn can_operation_tech_delta(bms: &BmsSnapshot, can: &mut CanController<'_>) -> Result<(), CanError>{
+    let can_cells = encode_tech_delta_frame(bms);
+    let frame_send = CanFrame::new(CanMsg::TechDelta.as_raw(), &can_cells);
     match can.write(&frame_send).await {
         Ok(_) => {}
-        
+        Err(CanError::Timeout) => return Err(CanError::Timeout),
+        Err(_) => return Err(CanError::WriteError),
+    }
 
-        Err(CanError::Timeout) => {
-            //info!("Timeout Can connection");
-            return Err(CanError::Timeout);
-        }
+    let can_temps = encode_tech_temps_frame(bms);
+    let frame_send = CanFrame::new(CanMsg::Tech4.as_raw(), &can_temps);
+    match can.write(&frame_send).await {
+        Ok(_) => Ok(()),
+        Err(CanError::Timeout) => Err(CanError::Timeout),
+        Err(_) => Err(CanError::WriteError),
+    }
+}
 
-        Err(_) => {
-            //info!("Can tech write error");
-            return Err(CanError::WriteError);
+// CellHistory frames for one cell, layout in encode_cell_history_frame
+pub async fn can_cell_history(cell: u8, history: &[u16; NUM_HISTORY], can: &mut CanController<'_>) -> Result<(), CanError> {
+    for frame in 0..NUM_HISTORY.div_ceil(3) {
+        let can_history = encode_cell_history_frame(cell, frame, history);
+        let frame_send = CanFrame::new(CanMsg::CellHistory.as_raw(), &can_history);
+        match can.write(&frame_send).await {
+            Ok(_) => {}
+            Err(CanError::Timeout) => return Err(CanError::Timeout),
+            Err(_) => return Err(CanError::WriteError),
         }
     }
+    Ok(())
+}
 
-    let can_third = [
-        get_byte!(bms.cell_volts(8), 0),
-        get_byte!(bms.cell_volts(8), 1),
-        get_byte!(bms.cell_volts(9), 0),
-        get_byte!(bms.cell_volts(9), 1),
-        get_byte!(bms.cell_volts(10), 0),
-        get_byte!(bms.cell_volts(10), 1),
-        get_byte!(bms.cell_volts(11), 0),
-        get_byte!(bms.cell_volts(11), 1)
-    ];
+// DiagnosticCounters frames, every counter, layout in encode_diagnostic_counter_frame
+pub async fn can_diagnostic_counters(counts: &[u32; Counter::COUNT], can: &mut CanController<'_>) -> Result<(), CanError> {
+    for (index, count) in counts.iter().enumerate() {
+        let can_counter = encode_diagnostic_counter_frame(index as u8, *count);
+        let frame_send = CanFrame::new(CanMsg::DiagnosticCounters.as_raw(), &can_counter);
+        match can.write(&frame_send).await {
+            Ok(_) => {}
+            Err(CanError::Timeout) => return Err(CanError::Timeout),
+            Err(_) => return Err(CanError::WriteError),
+        }
+    }
+    Ok(())
+}
 
-    embassy_time::Timer::after_millis(10).await;
+// PackSummary, layout in encode_pack_summary_frame
+pub async fn can_pack_summary(bms: &BmsSnapshot, fault_summary: u8, can: &mut CanController<'_>) -> Result<(), CanError> {
+    let can_summary = encode_pack_summary_frame(bms, fault_summary);
 
-    let frame_send = CanFrame::new(CanMsg::Tech3.as_raw(), &can_third);
+    let frame_send = CanFrame::new(CanMsg::PackSummary.as_raw(), &can_summary);
     match can.write(&frame_send).await {
-        Ok(_) => {}
+        Ok(_) => Ok(()),
+        Err(CanError::Timeout) => Err(CanError::Timeout),
+        Err(_) => Err(CanError::WriteError),
+    }
+}
 
-        Err(CanError::Timeout) => {
-            //info!("Timeout Can tech connection");
-            return Err(CanError::Timeout);
-        }
+// BalanceStatus, layout in encode_balance_status_frame
+pub async fn can_balance_status(active: bool, dry_run: bool, discharge_bitmap: u16, planned_bitmap: u16, state: BalancingState, can: &mut CanController<'_>) -> Result<(), CanError> {
+    let can_status = encode_balance_status_frame(active, dry_run, discharge_bitmap, planned_bitmap, state);
 
-        Err(_) => {
-            //info!("Can tech write error");
-            return Err(CanError::WriteError);
+    let frame_send = CanFrame::new(CanMsg::BalanceStatus.as_raw(), &can_status);
+    match can.write(&frame_send).await {
+        Ok(_) => Ok(()),
+        Err(CanError::Timeout) => Err(CanError::Timeout),
+        Err(_) => Err(CanError::WriteError),
+    }
+}
+
+// Thresholds, both pages, layout in encode_thresholds_frame
+pub async fn can_thresholds(thresholds: &Thresholds, can: &mut CanController<'_>) -> Result<(), CanError> {
+    for page in 0..2 {
+        let can_thresholds = encode_thresholds_frame(thresholds, page);
+        let frame_send = CanFrame::new(CanMsg::Thresholds.as_raw(), &can_thresholds);
+        match can.write(&frame_send).await {
+            Ok(_) => {}
+            Err(CanError::Timeout) => return Err(CanError::Timeout),
+            Err(_) => return Err(CanError::WriteError),
         }
     }
+    Ok(())
+}
 
-    let can_fourth = [
-        get_byte!(bms.temps(0), 0),
-        get_byte!(bms.temps(0), 1),
-        get_byte!(bms.temps(1), 0),
-        get_byte!(bms.temps(1), 1),
-        get_byte!(bms.temps(2), 0),
-        get_byte!(bms.temps(2), 1),
-        get_byte!(bms.temps(3), 0),
-        get_byte!(bms.temps(3), 1),
-    ];
+// SelfTestResult, layout in encode_diagnostics_frame
+pub async fn can_diagnostics(report: &DiagnosticReport, can: &mut CanController<'_>) -> Result<(), CanError> {
+    let can_result = encode_diagnostics_frame(report);
 
-    let frame_send = CanFrame::new(CanMsg::Tech4.as_raw(), &can_fourth);
+    let frame_send = CanFrame::new(CanMsg::SelfTestResult.as_raw(), &can_result);
     match can.write(&frame_send).await {
-        Ok(_) => {
-            Ok(())
-        }
+        Ok(_) => Ok(()),
+        Err(CanError::Timeout) => Err(CanError::Timeout),
+        Err(_) => Err(CanError::WriteError),
+    }
+}
 
-        Err(CanError::Timeout) => {
-            //info!("Timeout Can tech connection");
-            return Err(CanError::Timeout);
-        }
+// ErrorId, layout in encode_fault_status_frame. Confirmed: Ok only once the frame was acknowledged
+pub async fn can_fault_status(faults: u16, warnings: u8, severity: Option<Severity>, stale: bool, can: &mut CanController<'_>) -> Result<(), CanError> {
+    let can_faults = encode_fault_status_frame(faults, warnings, severity, stale);
 
-        Err(_) => {
-            //info!("Can tech write error");
-            return Err(CanError::WriteError);
-        }
+    let frame_send = CanFrame::new(CanMsg::ErrorId.as_raw(), &can_faults);
+    match can.write_confirmed(&frame_send).await {
+        Ok(_) => Ok(()),
+        Err(CanError::Timeout) => Err(CanError::Timeout),
+        Err(CanError::NotTransmitted) => Err(CanError::NotTransmitted),
+        Err(CanError::ArbitrationLost) => Err(CanError::ArbitrationLost),
+        Err(_) => Err(CanError::WriteError),
+    }
+}
+
+// UpdateTiming, layout in encode_update_timing_frame
+pub async fn can_update_timing(timing: &UpdateTiming, can: &mut CanController<'_>) -> Result<(), CanError> {
+    let can_timing = encode_update_timing_frame(timing);
+
+    let frame_send = CanFrame::new(CanMsg::UpdateTiming.as_raw(), &can_timing);
+    match can.write(&frame_send).await {
+        Ok(_) => Ok(()),
+        Err(CanError::Timeout) => Err(CanError::Timeout),
+        Err(_) => Err(CanError::WriteError),
     }
-}
\ No newline at end of file
+}
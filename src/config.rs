@@ -0,0 +1,271 @@
+// Every tunable of the board in one place: to set up a different car, start here.
+// Units follow the rest of the code: cells in 0.1mV (100uV), temperatures in 0.1°C,
+// current in SLAVEBMS::current() steps (1A = 10000, positive = discharge).
+// Chip constants (LTC6811 command codes, register bits, datasheet ranges) stay next to the driver.
+use crate::types::{CanMsg, DeciCelsius, DeciMilliAmp, DeciMilliVolt};
+use crate::types::fault::*;
+
+/*
+    Pack topology
+*/
+pub const NUM_CELLS: usize = 12;
+// GPIO1-4 direct, or every mux step times the analog GPIOs, see THERMISTOR_MUX_STEPS
+pub const NUM_TERMISTORS: usize = if THERMISTOR_MUX_STEPS == 0 {
+    4
+} else {
+    THERMISTOR_MUX_STEPS as usize * THERMISTOR_MUX_ANALOG.count_ones() as usize
+};
+pub const NUM_HISTORY: usize = 5; // readings averaged by SLAVEBMS
+// Wall-clock time each history slot stands for, the averaging window is NUM_HISTORY of them.
+// The LTC is read as fast as the loop runs, the last reading of each period is the one kept
+pub const HISTORY_PERIOD_MS: u64 = 100;
+
+/*
+    Cell limits
+*/
+pub const MAX_CELL_VOLTAGE: u16 = 42000;
+pub const MIN_CELL_VOLTAGE: u16 = 30000;
+// Above this a cell code is a broken read (0xFFFF reads as 6.5V), not an over-voltage
+pub const IMPLAUSIBLE_VOLTAGE: u16 = 50000;
+// 0.1 °C. Discharge limit of the cells, with margin
+pub const MAX_CELL_TEMP: u16 = 600;
+// 0.1 °C. The discharge limit of the cells (-20 °C) is below the 0 °C readings clamp at, so this
+// check can't trip. An open thermistor reads 0 too, WARN_THERMISTOR flags it against the die temperature
+pub const MIN_CELL_TEMP: u16 = 0;
+// Pack floor on tot_volt (100uV), independent of per-cell UV: below it the contactors stay open even
+// if every cell is above MIN_CELL_VOLTAGE. Default 38.4V (3.2V average), changeable over CAN
+pub const MIN_PACK_VOLTAGE: u32 = 384_000;
+pub const TEMP_RATE_WINDOW_MS: u64 = 1000;
+pub const MAX_TEMP_RATE: i32 = 20; // 0.1 °C/s, faster heating is treated as thermal runaway
+
+/*
+    Thermistors
+*/
+/// Resistance in ohm of the pull-up in front of each thermistor
+pub const RTHERMISTOR_OHM: u32 = 22_000;
+pub const R25: f32 = 9.914; // thermistor at 25°C, kOhm
+pub const B_COEFF: f32 = 3435.0; // thermistor Beta, K
+pub const MUX_SETTLE_MS: u64 = 1; // after switching the thermistor mux, before converting
+// Analog mux in front of the thermistors, driven by other LTC6811 GPIOs. GPIO bitmaps, bit 0 = GPIO1.
+// 0 steps = no mux, GPIO1-4 are direct thermistors. E.g. 8 thermistors on GPIO1-2 through a 4 way mux
+// selected by GPIO3-4: 4 steps, analog 0b0011, select 0b1100
+pub const THERMISTOR_MUX_STEPS: u8 = 0;
+pub const THERMISTOR_MUX_ANALOG: u8 = 0b0011; // GPIOs wired to the mux outputs, within GPIO1-4
+pub const THERMISTOR_MUX_SELECT: u8 = 0b1100; // GPIOs driving the mux select lines, LSB first
+const _: () = assert!(
+    THERMISTOR_MUX_STEPS == 0
+        || (THERMISTOR_MUX_ANALOG & !0b1111 == 0
+            && THERMISTOR_MUX_SELECT & !0b1_1111 == 0
+            && THERMISTOR_MUX_ANALOG & THERMISTOR_MUX_SELECT == 0
+            && THERMISTOR_MUX_STEPS as u32 <= 1 << THERMISTOR_MUX_SELECT.count_ones())
+);
+// Thermistor bitmaps (stale, topology) are u16
+const _: () = assert!(NUM_TERMISTORS <= 16);
+// Lookup table used instead of the Beta model when set, for NTCs the model does not fit.
+// Entries are (GPIO code in 100uV with VREF2 at its nominal 3V, temperature in 0.1°C), sorted by code
+// ascending, generated from the datasheet R/T curve and the pull-up. Codes between entries are
+// interpolated linearly, codes outside the table read as the nearest end
+pub const THERMISTOR_TABLE: Option<&[(u16, i16)]> = None;
+// Consecutive AUX conversions averaged into each thermistor reading, 1 = single shot
+pub const TEMP_OVERSAMPLES: u32 = 1;
+// Temperature readings after boot or an LTC reset during which the temperature and gradient
+// faults stay off: the first conversions after power-on are noisy and read as shorted thermistors
+pub const TEMP_WARMUP_READS: u32 = 5;
+// The LTC6811 die runs warmer than the board, but at rest not by much. A thermistor reading more than
+// THERMISTOR_DIE_MAX_DELTA below the die for THERMISTOR_DIE_DEBOUNCE_MS is most likely disconnected or
+// miswired in a way parse_temp can't tell from a real temperature. Only a warning, never a fault
+pub const THERMISTOR_DIE_MAX_DELTA: DeciCelsius = DeciCelsius::new(200); // 20°C
+pub const THERMISTOR_DIE_DEBOUNCE_MS: u64 = 5_000;
+
+/*
+    LTC6811 measurement checks
+*/
+pub const SC_TOLERANCE: u32 = 500; // 50mV in 100uV steps, allowed gap between sum of cells and SC
+pub const OPEN_WIRE_THRESHOLD: i32 = -4000; // -400mV, in 100uV steps
+
+/*
+    Balancing
+*/
+pub const BAL_EPSILON: i16 = 50; // allowable voltage difference for balancing
+pub const BAL_EPSILON_WARM: i16 = 150; // wider difference once the pack is warm, to avoid heating it further
+pub const BAL_WARM_TEMP: DeciCelsius = DeciCelsius::new(400); // max_temp (0.1°C) above which BAL_EPSILON_WARM applies
+pub const REST_CURRENT: DeciMilliAmp = DeciMilliAmp::new(10_000); // |current| (1A) below which the pack counts as resting
+pub const REST_SETTLE_MS: u64 = 10_000; // time at rest before cell voltages are trusted for balancing
+// Charge current (negative) down to which the cell voltages still count as settled for balancing: in the
+// CV taper at the top of charge the IR drop stays well under BAL_EPSILON. Above it balancing waits
+pub const BAL_CHARGE_CURRENT: DeciMilliAmp = DeciMilliAmp::new(-30_000); // 3A of charge
+pub const BAL_MIN_ON_MS: u64 = 5_000; // a discharging cell is not re-evaluated before this
+pub const BAL_COOLDOWN_MS: u64 = 2_000; // a cell that stopped discharging waits this before restarting
+pub const BAL_GAP_MS: u64 = 1_000; // no balancing decision for this long means balancing was stopped
+// Commanded balancing only runs at the top of charge: charging or at rest, with the highest cell at
+// or above BAL_START_VOLTAGE. It pauses again once the highest cell drops BAL_START_HYSTERESIS below
+pub const BAL_START_VOLTAGE: DeciMilliVolt = DeciMilliVolt::new(39_000); // 3.9V
+pub const BAL_START_HYSTERESIS: DeciMilliVolt = DeciMilliVolt::new(500);  // 50mV
+// Cells recover for a while once their bleed resistor is switched off: after muting the discharge
+// bits the measurement waits this long. Also the off time of each balancing cycle, keep it short
+pub const BAL_SETTLE_MS: u64 = 20;
+
+/*
+    Current sense
+*/
+pub const ADC_VREF_MV: f32 = 3300f32;
+pub const ADC_FULL_SCALE: f32 = 4095f32; // 12 bit
+pub const CURRENT_SENSOR_MV_PER_A: f32 = 9.2f32;
+pub const CURRENT_SCALE: f32 = 10000f32; // SLAVEBMS::current() steps per A
+pub const VOLTAGE_OFFSET: f32 = 1650f32; // sensor output at 0A, mV
+pub const CAL_SAMPLES: u32 = 100; // auto-zero window, one sample per ms
+pub const CAL_MAX_SPREAD: u16 = 40; // ADC counts (~32mV) between min and max sample
+pub const CAL_MAX_DEVIATION: f32 = 200f32; // mV from VOLTAGE_OFFSET
+pub const CAL_ATTEMPTS: u8 = 5;
+// Readings within ±2A are sensor noise and read as exactly 0.
+// Idle therefore has no sign: neither charge nor discharge, and the balancing rest gate sees 0
+pub const CURRENT_DEADBAND: f32 = 20_000f32;
+// Sign convention for current everywhere (SLAVEBMS, CAN): positive = discharge, negative = charge/regen.
+// Flip to -1 if the sensor is mounted the other way round
+pub const CURRENT_SIGN: f32 = 1f32;
+// Over-current, in two stages. Past the soft limits for CURRENT_WARN_DEBOUNCE_MS a warning goes out
+// in ErrorId so the driver can back off, past the hard limits for CURRENT_TRIP_DEBOUNCE_MS it is a fault
+pub const DISCHARGE_CURRENT_WARN: DeciMilliAmp = DeciMilliAmp::new(450_000); // 45A
+pub const CHARGE_CURRENT_WARN: DeciMilliAmp = DeciMilliAmp::new(-220_000);   // 22A of regen/charge
+pub const CURRENT_WARN_DEBOUNCE_MS: u64 = 100;
+pub const DISCHARGE_CURRENT_LIMIT: DeciMilliAmp = DeciMilliAmp::new(600_000); // 60A
+pub const CHARGE_CURRENT_LIMIT: DeciMilliAmp = DeciMilliAmp::new(-300_000);   // 30A of regen/charge
+pub const CURRENT_TRIP_DEBOUNCE_MS: u64 = 450;
+// Current sign plausibility. Under a steady current of at least POWER_CHECK_MIN_CURRENT the average
+// cell has to move the way the current says, down on discharge and up on charge. Moving the other way
+// by more than POWER_CHECK_WRONG_WAY over POWER_CHECK_WINDOW_MS means the sensor is mounted backwards
+// (see CURRENT_SIGN) or miswired. Only a warning, the current limits keep working on the reading as is
+pub const POWER_CHECK_MIN_CURRENT: DeciMilliAmp = DeciMilliAmp::new(100_000); // 10A
+pub const POWER_CHECK_WINDOW_MS: u64 = 5_000;
+pub const POWER_CHECK_WRONG_WAY: DeciMilliVolt = DeciMilliVolt::new(50); // 5mV
+// A live ADC channel always jitters by a few codes. This many windows (~20ms each) with every raw
+// sample identical, while the average cell moved by more than CURRENT_FROZEN_CELL_DELTA (0.1mV),
+// means the current channel is stuck and the reported current is meaningless
+pub const CURRENT_FROZEN_WINDOWS: u32 = 100;
+pub const CURRENT_FROZEN_CELL_DELTA: DeciMilliVolt = DeciMilliVolt::new(200);
+// current_sense gives one reading per ~20ms window, which the over-current trip uses as is.
+// The averaged current follows it as a moving average over about this many windows (~0.6s),
+// for the consumers that want a steady value rather than transients
+pub const CURRENT_AVG_WINDOWS: i32 = 32;
+
+/*
+    Pack voltage sense
+*/
+// On its own ADC, through a resistor divider from the pack terminals
+pub const PACK_DIVIDER_RATIO: f32 = 16f32; // pack volts per ADC pin volt
+// Sensed pack and the sum of the cell taps may differ by this much (100uV, 1V) before it is a fault:
+// a blown fuse, an open tap or a broken measurement on either side
+pub const PACK_MISMATCH_LIMIT: u32 = 10_000;
+
+/*
+    Safety output
+*/
+// Level of err_check (PA2) that tells the safety chain there is a fault. false: high = healthy,
+// low = fault, true: active-high fault. Boot, shutdown and every fault drive the fault level
+pub const ERR_CHECK_FAULT_HIGH: bool = false;
+
+/*
+    Fault handling
+*/
+// A voltage/temperature/flag condition has to hold this long before it becomes a fault
+pub const FAULT_DEBOUNCE_MS: u64 = 450;
+// Cell UV/OV is judged per reading, not on the history average: it trips once VOLT_TRIP_SAMPLES
+// of the last VOLT_TRIP_WINDOW readings had a cell out of range, by our own check or by the
+// LTC6811 UV/OV flags. A single glitch in either can't open the loop while a real excursion
+// still does in a few LTC cycles. FAULT_DEBOUNCE_MS applies on top
+pub const VOLT_TRIP_SAMPLES: u32 = 3;
+pub const VOLT_TRIP_WINDOW: u32 = 5;
+const _: () = assert!(VOLT_TRIP_SAMPLES >= 1 && VOLT_TRIP_SAMPLES <= VOLT_TRIP_WINDOW && VOLT_TRIP_WINDOW <= 32);
+// How long cells of a failing register group may stay unmonitored before it is a fault
+pub const STALE_CELLS_MAX_MS: u64 = 2000;
+// Consecutive failed LTC updates tolerated on stale data before communication counts as lost
+pub const LTC_MAX_FAILURES: u32 = 5;
+// Period of the cells/temps/faults log
+pub const LOG_PERIOD_MS: u64 = 1000;
+// Period of the effective configuration dump, only built with the config-log feature
+#[cfg_attr(not(feature = "config-log"), allow(unused))]
+pub const CONFIG_LOG_PERIOD_MS: u64 = 10_000;
+// No successful LTC update for this long (plus LOW_POWER_LTC_PERIOD_MS in low power) and ErrorId
+// flags the measurements as stale. Measurement frames already stop, they never repeat a sample
+pub const MEASUREMENT_STALE_MS: u64 = 1000;
+// Blink period of the debug LED while a fault is active
+pub const FAULT_LED_BLINK_MS: u64 = 200;
+
+/// Reaction of ltc_function to each fault class, one entry per FAULT_* bit. Every fault is
+/// reported in ErrorId and stops balancing, the table decides the rest. A fault with
+/// open_loop false is reported without opening the safety loop: the Derate ones leave the power
+/// limit to the VCU, a Warning is report only
+pub const FAULT_RESPONSES: &[FaultResponse] = &[
+    FaultResponse { fault: FAULT_VOLT, latch: false, open_loop: true, severity: Severity::Critical, led: FaultLed::Voltage },
+    FaultResponse { fault: FAULT_PACK_UV, latch: false, open_loop: true, severity: Severity::Critical, led: FaultLed::None },
+    FaultResponse { fault: FAULT_TEMP, latch: false, open_loop: true, severity: Severity::Critical, led: FaultLed::Temperature },
+    FaultResponse { fault: FAULT_GRADIENT, latch: true, open_loop: true, severity: Severity::Critical, led: FaultLed::Temperature },
+    FaultResponse { fault: FAULT_CURRENT, latch: false, open_loop: true, severity: Severity::Critical, led: FaultLed::None },
+    FaultResponse { fault: FAULT_COMM, latch: false, open_loop: true, severity: Severity::Critical, led: FaultLed::None },
+    FaultResponse { fault: FAULT_SPI_CS, latch: false, open_loop: true, severity: Severity::Critical, led: FaultLed::None },
+    FaultResponse { fault: FAULT_STALE, latch: false, open_loop: true, severity: Severity::Critical, led: FaultLed::None },
+    // Implausible cell codes, the reading is dropped. Lost or stale cells are FAULT_COMM/FAULT_STALE
+    FaultResponse { fault: FAULT_READ, latch: false, open_loop: false, severity: Severity::Warning, led: FaultLed::None },
+    FaultResponse { fault: FAULT_FLAGS, latch: false, open_loop: false, severity: Severity::Derate, led: FaultLed::None },
+    FaultResponse { fault: FAULT_SUM, latch: false, open_loop: false, severity: Severity::Derate, led: FaultLed::None },
+    FaultResponse { fault: FAULT_PACK, latch: false, open_loop: false, severity: Severity::Derate, led: FaultLed::None },
+    FaultResponse { fault: FAULT_CURRENT_ADC, latch: false, open_loop: false, severity: Severity::Derate, led: FaultLed::None },
+];
+
+/*
+    Low power
+*/
+// Parked car: no CAN traffic, no current, no balancing and the average cell within IDLE_VOLT_DELTA
+// (0.1mV) for IDLE_TIMEOUT_MS. The LTC6811 is then read every LOW_POWER_LTC_PERIOD_MS, longer than
+// its ~2s watchdog so it drops into SLEEP in between, and the periodic frames slow down by
+// LOW_POWER_TX_FACTOR. Any CAN frame or current above REST_CURRENT wakes everything up
+pub const IDLE_TIMEOUT_MS: u64 = 60_000;
+pub const IDLE_VOLT_DELTA: DeciMilliVolt = DeciMilliVolt::new(20);
+pub const LOW_POWER_LTC_PERIOD_MS: u64 = 5_000;
+pub const LOW_POWER_TX_FACTOR: u64 = 10;
+
+/*
+    CAN
+*/
+pub const CAN_BITRATE: u32 = 500_000;
+/// Transmit period of each periodic frame, in ms. `send_can` walks this
+/// table every `TX_TICK_MS` and sends whatever is due.
+/// `Tech1` stands for the whole Tech1..Tech4 set, only sent in tech mode.
+pub const TX_PERIODS_MS: [(CanMsg, u64); 10] = [
+    (CanMsg::ErrorId, 100),
+    (CanMsg::VoltageId, 200),
+    (CanMsg::TemperatureId, 200),
+    (CanMsg::AvgTemperatureId, 1000),
+    (CanMsg::Tech1, 200),
+    (CanMsg::BalanceStatus, 500),
+    (CanMsg::UpdateTiming, 1000),
+    (CanMsg::PackVoltage, 200),
+    (CanMsg::PackSummary, 200),
+    (CanMsg::ThermistorDetail, THERMISTOR_DETAIL_PERIOD_MS),
+];
+
+/// Period of ThermistorDetail, the four thermistors outside tech mode.
+/// Temperatures move slowly, the thermal logger does not need more than 1 Hz
+pub const THERMISTOR_DETAIL_PERIOD_MS: u64 = 1000;
+
+/// Commands `read_can` acts on, each with a handler in `dispatch_rx`.
+/// Any other ID on the bus takes the single unhandled path.
+pub const RX_COMMANDS: &[CanMsg] = &[
+    CanMsg::Balancing,
+    CanMsg::Tech,
+    CanMsg::RunSelfTest,
+    CanMsg::ForceBalance,
+    CanMsg::ManualDischarge,
+    CanMsg::RequestCellHistory,
+    CanMsg::SetPackMinVoltage,
+    CanMsg::GetThresholds,
+    CanMsg::SetTopology,
+    CanMsg::GetDiagnostics,
+    CanMsg::ResetLtc,
+    #[cfg(feature = "fault-injection")]
+    CanMsg::InjectFault,
+    CanMsg::PrepareShutdown,
+];
+
+/// Scheduler resolution, every period above should be a multiple of it
+pub const TX_TICK_MS: u64 = 10;
@@ -1,9 +1,18 @@
 // IMPORT
 
 use super::spi_device::SpiDevice;
-use crate::types::{bms::SLAVEBMS, VOLTAGES};
+use embedded_hal_1::digital::OutputPin;
+use embedded_hal_async::spi::SpiBus;
+use crate::types::diagnostics::{Counter, DIAGNOSTICS};
+use crate::types::{bms::{SLAVEBMS, NUM_CELLS, NUM_TERMISTORS}, DeciCelsius, DeciMilliAmp, DeciMilliVolt, IMPLAUSIBLE_VOLTAGE, VOLTAGES};
+use crate::config::{
+    B_COEFF, BAL_CHARGE_CURRENT, BAL_COOLDOWN_MS, BAL_EPSILON, BAL_EPSILON_WARM, BAL_GAP_MS, BAL_MIN_ON_MS, BAL_SETTLE_MS,
+    BAL_WARM_TEMP, MUX_SETTLE_MS, OPEN_WIRE_THRESHOLD, R25, REST_CURRENT, REST_SETTLE_MS, RTHERMISTOR_OHM, SC_TOLERANCE,
+    TEMP_OVERSAMPLES, TEMP_WARMUP_READS, THERMISTOR_MUX_ANALOG, THERMISTOR_MUX_SELECT, THERMISTOR_MUX_STEPS,
+    THERMISTOR_TABLE,
+};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 
 use libm::{roundf, logf}; // libm helper functions
 
@@ -34,39 +43,80 @@ pub const RDAUXA: [u8; 2] = [0x00, 0x0C];
 /// Read Auxiliary Register Group B (for temperature)
 pub const RDAUXB: [u8; 2] = [0x00, 0x0E];
 
+/// Read Status Register Group A (SC, ITMP, VA)
+pub const RDSTATA: [u8; 2] = [0x00, 0x10];
+
+/// Read Status Register Group B (VD, cell UV/OV flags, MUXFAIL, THSD)
+pub const RDSTATB: [u8; 2] = [0x00, 0x12];
+
+/// ADC mode (MD bits, command bits 8-7) of the cell conversions ADCV and ADCVSC, see ADC_MODE_HZ
+const CELL_MD: u8 = 0b00;
+
 /// Start Voltage Converstion
-pub const ADCV: [u8; 2] = [0x02, 0x60];
+#[allow(unused)]
+pub const ADCV: [u8; 2] = [0x02 | (CELL_MD >> 1), 0x60 | ((CELL_MD & 0x01) << 7)];
+
+/// Start Combined Cell Voltage and Sum of Cells Conversion
+pub const ADCVSC: [u8; 2] = [0x04 | (CELL_MD >> 1), 0x67 | ((CELL_MD & 0x01) << 7)];
 
 /// Start Temperature Converstion
 pub const ADAX: [u8; 2] = [0x04, 0x80];
 
+/// Start Status Group ADC Conversion (SC, ITMP, VA, VD)
+pub const ADSTAT: [u8; 2] = [0x04, 0x68];
+
 /// Polling Completed Temperature Conversion
 pub const PLADC: [u8; 2] = [0x7, 0x14];
 
+/// Start Cell Voltage Self Test (ST = 01)
+pub const CVST: [u8; 2] = [0x02, 0x27];
 
-/*
-    Various constants
-*/
-/// Resistance in ohm of 
-const RTHERMISTOR_OHM: u32 = 22_000;
+/// Start Auxiliary Self Test (ST = 01)
+pub const AXST: [u8; 2] = [0x04, 0x27];
+
+/// Start Open Wire Conversion with pull-up current
+pub const ADOW_PUP: [u8; 2] = [0x02, 0x68];
 
-const R25: f32 = 9.914;
+/// Start Open Wire Conversion with pull-down current
+pub const ADOW_PDN: [u8; 2] = [0x02, 0x28];
 
-// Coefficiente Beta del termistore (in Kelvin)
-const B_COEFF: f32     = 3435.0;
+
+/*
+    Various constants, the tunable ones (thermistor, balancing, checks) are in crate::config
+*/
 // Conversione da Kelvin a Celsius
 const KELVIN_2_CELSIUS: f32 = 273.15;
 
 // Valori speciali di saturazione / guasto
 const MAX_TEMP: u16 = u16::MAX;  // OverTemp (corto a massa)
 const MIN_TEMP: u16 = 0;      
-// Thresholds and balancing parameters (example values – adjust as required)\
-const BAL_EPSILON: i16 = 50; // allowable voltage difference for balancing
+// RDCFGA reads check_cs needs before calling a constant answer a stuck CS
+const CS_CHECK_READS: usize = 3;
+// VREF2 code (100uV) the THERMISTOR_TABLE codes refer to
+const VREF2_NOMINAL: u16 = 30000;
 
 // Configuration
-const NUM_CELLS: usize = 12;
 const REFON: u8 = 0x01 << 2;// Reference Powered Up
 const ADCOPT: u8 = 0x00; // ADC Mode option bit
+
+// Cell ADC sampling rate selected by CELL_MD together with ADCOPT, in Hz
+pub const ADC_MODE_HZ: u32 = match (CELL_MD, ADCOPT != 0) {
+    (0b00, false) => 422,
+    (0b00, true) => 1_000,
+    (0b01, false) => 27_000,
+    (0b01, true) => 14_000,
+    (0b10, false) => 7_000,
+    (0b10, true) => 3_000,
+    (_, false) => 26,
+    (_, true) => 2_000,
+};
+// Longest a conversion may take before the PLADC poll gives up. The longest one, all cells plus SC,
+// is ~13ms in the 422Hz mode, ~200ms at 26Hz and under 8ms in the faster modes
+const ADC_POLL_TIMEOUT_MS: u64 = match ADC_MODE_HZ {
+    26 => 250,
+    422 => 20,
+    _ => 10,
+};
                          // GPIO configuration bits if needed
 const GPIO1: u8 = 0x01; // GPIO1 as digital input
 const GPIO2: u8 = 0x01; // GPIO2 as digital input
@@ -75,7 +125,39 @@ const GPIO4: u8 = 0x01; // GPIO4 as digital input
 const GPIO5: u8 = 0x00; // GPIO5 as digital input
 const GPIOS: u8 = 0x0 | (GPIO1 << 3) | (GPIO2 << 4) | (GPIO3 << 5) | (GPIO4 << 6) | (GPIO5 << 7);
 
-#[allow(unused)]
+// Self test
+const SELF_TEST_PATTERN: u16 = 0x9555; // expected ADC output for ST = 01, MD = 00
+const CFGR0_READBACK_MASK: u8 = REFON | ADCOPT; // GPIO bits read back the pin level, DTEN is read only
+
+// Digital supply (VREGD) operating range, 100uV steps
+const VD_MIN: u16 = 27_000;
+const VD_MAX: u16 = 36_000;
+
+/// Initial PEC remainder the LTC6811 starts every command and register group from (datasheet, PEC calculation)
+pub const PEC_SEED: u16 = 16;
+
+/// PEC of `data` as sent on the wire, MSB first. CRC15 with polynomial
+/// x^15 + x^14 + x^10 + x^8 + x^7 + x^4 + x^3 + 1 (0x4599), seeded with PEC_SEED and computed a byte at a
+/// time through CRC15_TABLE. The 15 bit result is shifted left once: the PEC is 16 bits with a 0 LSB.
+/// const so tooling and checks can precompute expected PECs, e.g. `pec15(&WRCFGA)`
+pub const fn pec15(data: &[u8]) -> [u8; 2] {
+    let mut remainder = PEC_SEED;
+    let mut i = 0;
+    while i < data.len() {
+        let address = (((remainder >> 7) ^ data[i] as u16) & 0xff) as usize;
+        remainder = (remainder << 8) ^ CRC15_TABLE[address];
+        i += 1;
+    }
+
+    // The CRC15 has a 0 in the LSB
+    remainder <<= 1;
+
+    [(remainder >> 8) as u8, remainder as u8]
+}
+
+// Datasheet example: RDCFGA carries PEC 0x2B0A
+const _: () = assert!(matches!(pec15(&RDCFGA), [0x2B, 0x0A]));
+
 const CRC15_TABLE: [u16; 256] = [
     0x0, 0xc599, 0xceab, 0xb32, 0xd8cf, 0x1d56, 0x1664, 0xd3fd, 0xf407, 0x319e, 0x3aac, 0xff35,
     0x2cc8, 0xe951, 0xe263, 0x27fa, 0xad97, 0x680e, 0x633c, 0xa6a5, 0x7558, 0xb0c1, 0xbbf3, 0x7e6a,
@@ -107,17 +189,280 @@ pub enum MODE {
     BALANCING,
 }
 
-// LTC6811 Management structure
-pub struct LTC6811 {
-    spi: &'static Mutex<CriticalSectionRawMutex, SpiDevice<'static>>,
+/// Whether balancing is running and, if not, why it last stopped.
+/// Set by `set_mode(MODE::BALANCING)` and `stop_balancing`, sent as byte 5 of BalanceStatus.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BalancingState {
+    #[default]
+    Inactive,        // never started since boot, or switched off by command or shutdown
+    Active,
+    Waiting,         // commanded, but outside the top-of-charge window or the cells haven't settled
+    StoppedBalanced, // check_need_balance found every cell within the balancing threshold
+    StoppedFault,    // aborted by a fault
+    StoppedTimeout,  // ForceBalance ran out
+}
+
+/// Outcome of `LTC6811::run_diagnostics`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiagnosticReport {
+    pub cell_test: bool,      // CVST: all cell registers hold the test pattern
+    pub aux_test: bool,       // AXST: all GPIO and REF registers hold the test pattern
+    pub open_wire_test: bool, // ADOW: conversions ran and no input is open
+    pub open_wire: u16,       // bit n set = C(n) pin open, C0..C12
+    pub config_test: bool,    // RDCFGA matches the configuration we wrote
+    pub status_test: bool,    // RDSTATB: VD in range, no MUXFAIL, no THSD
+}
+
+impl DiagnosticReport {
+    pub fn passed(&self) -> bool {
+        self.cell_test && self.aux_test && self.open_wire_test && self.config_test && self.status_test
+    }
+}
+
+/// Decoded Status Register Group B
+/// The analog supply (VA) lives in Status Register Group A
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StatusB {
+    pub vd: u16,               // digital supply voltage, 100uV steps
+    pub cell_uv: u16,          // bit n set = cell n+1 below VUV
+    pub cell_ov: u16,          // bit n set = cell n+1 above VOV
+    pub mux_fail: bool,        // multiplexer self test failed
+    pub thermal_shutdown: bool,
+    pub revision: u8,
+}
+
+impl StatusB {
+    pub fn from_register(data: &[u8; 6]) -> Self {
+        let mut cell_uv: u16 = 0;
+        let mut cell_ov: u16 = 0;
+        // STBR2..STBR4 hold 4 cells each: CxUV at bit 2k, CxOV at bit 2k+1
+        for i in 0..NUM_CELLS {
+            let flags = data[2 + i / 4] >> ((i % 4) * 2);
+            cell_uv |= ((flags & 0x01) as u16) << i;
+            cell_ov |= (((flags >> 1) & 0x01) as u16) << i;
+        }
+
+        StatusB {
+            vd: u16::from_le_bytes([data[0], data[1]]),
+            cell_uv,
+            cell_ov,
+            mux_fail: data[5] & 0x02 != 0,
+            thermal_shutdown: data[5] & 0x01 != 0,
+            revision: data[5] >> 4,
+        }
+    }
+
+    pub fn vd_in_range(&self) -> bool {
+        (VD_MIN..=VD_MAX).contains(&self.vd)
+    }
+}
+
+/// Balancing decision parameters
+#[derive(Debug, Clone, Copy)]
+pub struct BalanceConfig {
+    pub epsilon: i16,           // a cell more than this above the lowest one gets discharged
+    pub warm_epsilon: i16,      // epsilon used when max_temp is above warm_temp
+    pub warm_temp: Option<DeciCelsius>, // None = epsilon does not depend on temperature
+}
+
+impl Default for BalanceConfig {
+    fn default() -> Self {
+        BalanceConfig {
+            epsilon: BAL_EPSILON,
+            warm_epsilon: BAL_EPSILON_WARM,
+            warm_temp: Some(BAL_WARM_TEMP),
+        }
+    }
+}
+
+impl BalanceConfig {
+    pub fn epsilon(&self, max_temp: DeciCelsius) -> i16 {
+        match self.warm_temp {
+            Some(warm_temp) if max_temp > warm_temp => self.warm_epsilon,
+            _ => self.epsilon,
+        }
+    }
+}
+
+/// Tracks how long the pack has been at rest, so balancing only decides on relaxed cell voltages.
+/// A gentle charge counts as rest too, the top of charge is where balancing runs
+#[derive(Debug, Clone, Copy)]
+pub struct RestGate {
+    pub threshold: DeciMilliAmp, // discharge below this counts as rest
+    pub charge_threshold: DeciMilliAmp, // and so does charge above this (negative) one
+    pub settle_ms: u64, // time at rest before the voltages are trusted
+    rest_since: Option<u64>,
+}
+
+impl Default for RestGate {
+    fn default() -> Self {
+        RestGate {
+            threshold: REST_CURRENT,
+            charge_threshold: BAL_CHARGE_CURRENT,
+            settle_ms: REST_SETTLE_MS,
+            rest_since: None,
+        }
+    }
+}
+
+impl RestGate {
+    // Feed the latest current, returns true once the pack has rested for settle_ms
+    pub fn update(&mut self, current: DeciMilliAmp, now_ms: u64) -> bool {
+        if current >= self.threshold || current <= self.charge_threshold {
+            self.rest_since = None;
+            return false;
+        }
+        let since = *self.rest_since.get_or_insert(now_ms);
+        now_ms - since >= self.settle_ms
+    }
+}
+
+/// Minimum on-time and cooldown of the discharge FETs, so balancing decisions don't chatter.
+/// Only shapes the balancing bitmap: faults and manual discharge bypass it
+#[derive(Debug, Clone, Copy)]
+pub struct FetHold {
+    pub min_on_ms: u64,
+    pub cooldown_ms: u64,
+    on_since: [Option<u64>; NUM_CELLS],  // Some while the cell is discharging
+    off_since: [Option<u64>; NUM_CELLS], // Some once the cell stopped discharging
+    last_ms: Option<u64>,                // time of the last apply()
+}
+
+impl Default for FetHold {
+    fn default() -> Self {
+        FetHold {
+            min_on_ms: BAL_MIN_ON_MS,
+            cooldown_ms: BAL_COOLDOWN_MS,
+            on_since: [None; NUM_CELLS],
+            off_since: [None; NUM_CELLS],
+            last_ms: None,
+        }
+    }
+}
+
+impl FetHold {
+    // Filter the requested discharge bitmap, returns the bitmap to write
+    pub fn apply(&mut self, requested: u16, now_ms: u64) -> u16 {
+        // Balancing was stopped in between: whatever was on went off at the last decision
+        if let Some(last) = self.last_ms {
+            if now_ms - last > BAL_GAP_MS {
+                for i in 0..NUM_CELLS {
+                    if self.on_since[i].take().is_some() {
+                        self.off_since[i] = Some(last);
+                    }
+                }
+            }
+        }
+        self.last_ms = Some(now_ms);
+
+        let mut bitmap = 0u16;
+        for i in 0..NUM_CELLS {
+            let wanted = requested & (1 << i) != 0;
+            let on = match (self.on_since[i], self.off_since[i]) {
+                (Some(since), _) if now_ms - since < self.min_on_ms => true,
+                (Some(_), _) => {
+                    if !wanted {
+                        self.on_since[i] = None;
+                        self.off_since[i] = Some(now_ms);
+                    }
+                    wanted
+                }
+                (None, Some(since)) if now_ms - since < self.cooldown_ms => false,
+                (None, _) => {
+                    if wanted {
+                        self.on_since[i] = Some(now_ms);
+                    }
+                    wanted
+                }
+            };
+            if on {
+                bitmap |= 1 << i;
+            }
+        }
+        bitmap
+    }
+
+    // How long the cell has been discharging in the current stretch, 0 if it is off
+    pub fn _on_time_ms(&self, cell: usize, now_ms: u64) -> u64 {
+        self.on_since[cell].map_or(0, |since| now_ms - since)
+    }
+}
+
+/// Analog mux in front of the thermistor inputs, selected through other LTC6811 GPIOs.
+/// Each step converts all `analog` GPIOs, so `steps` x analog inputs thermistors are read,
+/// NUM_TERMISTORS of them. GPIO bitmaps use bit 0 = GPIO1
+#[derive(Debug, Clone, Copy)]
+pub struct ThermistorMux {
+    pub analog: u8, // GPIOs wired to the mux outputs
+    pub select: u8, // GPIOs driving the mux select lines, LSB first
+    pub steps: u8,  // mux positions to walk through
+}
+
+impl ThermistorMux {
+    // The mux wired on this board, from config::THERMISTOR_MUX_*. None = no mux
+    pub const BOARD: Option<ThermistorMux> = if THERMISTOR_MUX_STEPS == 0 {
+        None
+    } else {
+        Some(ThermistorMux { analog: THERMISTOR_MUX_ANALOG, select: THERMISTOR_MUX_SELECT, steps: THERMISTOR_MUX_STEPS })
+    };
+}
+
+/// Duration of `LTC6811::update`, as measured by the caller
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UpdateTiming {
+    pub last_us: u32,
+    pub avg_us: u32, // exponential average, each sample weighs 1/8
+    pub max_us: u32,
+}
+
+impl UpdateTiming {
+    pub fn record(&mut self, us: u32) {
+        self.last_us = us;
+        self.max_us = self.max_us.max(us);
+        self.avg_us = if self.avg_us == 0 {
+            us
+        } else {
+            self.avg_us - self.avg_us / 8 + us / 8
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LtcError {
+    Read,       // SPI transfer or PEC failure
+    SumOfCells, // cells read one by one don't add up to the chip's SC
+    ImplausibleCell(u16), // bitmap of cells read above IMPLAUSIBLE_VOLTAGE
+}
+
+// LTC6811 Management structure, generic over the SPI transport (board::Ltc on this board)
+pub struct LTC6811<SPI: 'static, CS: 'static> {
+    spi: &'static Mutex<CriticalSectionRawMutex, SpiDevice<SPI, CS>>,
     bms: &'static Mutex<CriticalSectionRawMutex, SLAVEBMS>,
     config: [u8; 6], // Configuration registers
     mode: MODE,
-    prev_mode: MODE
+    prev_mode: MODE,
+    balance_config: BalanceConfig,
+    balance_dry_run: bool, // compute the discharge bitmap but never write it
+    planned_bitmap: u16,   // last bitmap computed in balancing mode, written or not
+    rest_gate: RestGate,
+    fet_hold: FetHold,
+    balance_time_ms: [u32; NUM_CELLS], // time each discharge bit spent set since boot or the last reset
+    last_cfg_ms: Option<u64>,          // when the discharge bits were last written
+    sc_tolerance: u32, // 100uV
+    last_vref: Option<u16>,  // last VREF2 code read with a good AUXB PEC
+    stale_thermistors: u16,  // thermistors skipped in the last reading, bit i = GPIO i+1
+    temp_reads: u32,         // temperature readings since boot or the last reset, see TEMP_WARMUP_READS
+    stale_cells: u16,        // cells whose register group failed PEC in the last reading
+    manual_discharge: Option<u16>, // bench override of the discharge bitmap, wins over balancing
+    update_timing: UpdateTiming,
+    thermistor_mux: Option<ThermistorMux>, // None = GPIO1-4 are direct thermistors
+    cs_fault: bool, // check_cs found SDO stuck, CS is most likely shorted or open
+    die_temp: Option<DeciCelsius>, // ITMP from the last update, None if the read failed
+    balancing_state: BalancingState,
 }
-impl LTC6811 {
+impl<SPI: SpiBus, CS: OutputPin> LTC6811<SPI, CS> {
     pub async fn new(
-        spi: &'static Mutex<CriticalSectionRawMutex, SpiDevice<'static>>,
+        spi: &'static Mutex<CriticalSectionRawMutex, SpiDevice<SPI, CS>>,
         bms: &'static Mutex<CriticalSectionRawMutex, SLAVEBMS>,
     ) -> Self {
         // Initialize with default configuration
@@ -142,25 +487,36 @@ impl LTC6811 {
             config,
             mode: MODE::NORMAL,
             prev_mode: MODE::NORMAL,
+            balance_config: BalanceConfig::default(),
+            balance_dry_run: false,
+            planned_bitmap: 0,
+            rest_gate: RestGate::default(),
+            fet_hold: FetHold::default(),
+            balance_time_ms: [0; NUM_CELLS],
+            last_cfg_ms: None,
+            sc_tolerance: SC_TOLERANCE,
+            last_vref: None,
+            stale_thermistors: 0,
+            temp_reads: 0,
+            stale_cells: 0,
+            manual_discharge: None,
+            update_timing: UpdateTiming::default(),
+            thermistor_mux: ThermistorMux::BOARD,
+            cs_fault: false,
+            die_temp: None,
+            balancing_state: BalancingState::Inactive,
         }
     }
 
-    // Calculate PEC (CRC) for LTC6811 communication
+    // Calculate PEC (CRC) for LTC6811 communication, see pec15
     pub fn calculate_pec(&self, data: &[u8]) -> [u8; 2] {
-        let mut remainder: u16 = 16;
-
-        for byte in data {
-            let address: usize = (((remainder >> 7) ^ (*byte as u16)) & 0xff).into();
-            remainder = (remainder << 8) ^ CRC15_TABLE[address];
-        }
-
-        // The CRC15 has a 0 in the LSB
-        remainder <<= 1;
-
-        [(remainder >> 8) as u8, remainder as u8]
+        pec15(data)
     }
 
     pub async fn set_mode(&mut self, mode: MODE) {
+        if mode == MODE::BALANCING {
+            self.balancing_state = BalancingState::Active;
+        }
         self.mode = mode.clone();
         if self.prev_mode != mode || mode == MODE::BALANCING{
             let _ = self.init_cfg().await;
@@ -185,20 +541,33 @@ impl LTC6811 {
         self.config[1] = (uv_val & 0xFF) as u8;
         self.config[2] = (((ov_val & 0xF) << 4) | ((uv_val & 0xF00) >> 8)) as u8;
         self.config[3] = (ov_val >> 4) as u8;
+        self.accumulate_balance_time(Instant::now().as_millis());
         {
             let bms_data = self.bms.lock().await;
             // Assume bms_data.min_volt and bms_data.max_volt are set when valid.
-            if self.mode == MODE::BALANCING && bms_data.min_volt() != 0 && bms_data.max_volt() != 0
+            if let Some(manual) = self.manual_discharge {
+                // Kept in every mode until cleared, measurements included
+                self.config[4] = (manual & 0xFF) as u8;
+                self.config[5] = ((manual >> 8) & 0x0F) as u8;
+            } else if self.mode == MODE::BALANCING && bms_data.min_volt() != DeciMilliVolt::default() && bms_data.max_volt() != DeciMilliVolt::default()
             {
-                let mut discharge_bitmap: u16 = 0;
-                // Iterate over all 12 cells. Here we assume that bms_data.cell_volts is an array of 12 u16.
-                for i in 0..NUM_CELLS {
-                    // If the cell voltage exceeds the minimum by more than BAL_EPSILON, enable discharge.
-                    if (bms_data.cell_volts(i) as i16 - bms_data.min_volt() as i16)
-                        > BAL_EPSILON
-                    {
-                        discharge_bitmap |= 1 << i;
+                // Under load the IR drop skews the cells, keep the last decision until the pack settles
+                let now_ms = Instant::now().as_millis();
+                let at_rest = self.rest_gate.update(bms_data.current(), now_ms);
+                let requested = if at_rest {
+                    self.compute_discharge_bitmap(&bms_data)
+                } else {
+                    self.planned_bitmap
+                };
+                let mut discharge_bitmap = self.fet_hold.apply(requested, now_ms);
+                if self.balance_dry_run {
+                    if discharge_bitmap != self.planned_bitmap {
+                        self.log_dry_run(&bms_data, discharge_bitmap);
                     }
+                    self.planned_bitmap = discharge_bitmap;
+                    discharge_bitmap = 0;
+                } else {
+                    self.planned_bitmap = discharge_bitmap;
                 }
                 // In the C code the lower 8 bits go into config[4] and the upper nibble (4 bits) goes into config[5].
                 self.config[4] = (discharge_bitmap & 0xFF) as u8;
@@ -225,37 +594,98 @@ impl LTC6811 {
         // Delay to allow LTC6811 to stabilize
         Timer::after(Duration::from_millis(10)).await;
 
-        // Verify configuration
-        let mut read_config = [0u8; 8]; // 6 config bytes + 2 PEC bytes
+        if self.check_cs().await {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    // Heuristic CS integrity check, right after the configuration was written.
+    // With CS stuck high the chip never drives SDO and every byte reads the pull-up 0xFF,
+    // with CS stuck low it never sees a command boundary and answers nothing, the bus reads
+    // one constant byte. A single RDCFGA with a good PEC proves the line works; CS_CHECK_READS
+    // answers that are all the same byte flag a CS fault. Anything else is left to the PEC checks
+    pub async fn check_cs(&mut self) -> bool {
         let cmd = self.prepare_command(RDCFGA);
-        let mut spi_data = self.spi.lock().await;
-        // spi_data.write(&cmd).await;
-        // self.transfer_ltc(&mut spi_data, &mut read_config).await;
-        spi_data.cmd_read(&cmd, &mut read_config).await.unwrap();
-        drop(spi_data);
+        let mut first: Option<u8> = None;
+        let mut constant = true;
+        for _ in 0..CS_CHECK_READS {
+            let mut data = [0u8; 8]; // 6 data bytes + 2 PEC bytes
+            self.wakeup_idle().await;
+            let mut spi_data = self.spi.lock().await;
+            let result = spi_data.cmd_read(&cmd, &mut data).await;
+            drop(spi_data);
+            if result.is_err() {
+                constant = false;
+                continue;
+            }
+            if [data[6], data[7]] == self.calculate_pec(&data[0..6]) {
+                self.cs_fault = false;
+                return true;
+            }
+            let byte = *first.get_or_insert(data[0]);
+            constant &= data.iter().all(|&b| b == byte);
+        }
 
-        // Config verification could be done here if needed
+        self.cs_fault = constant;
+        if constant {
+            defmt::error!("LTC6811 answers a constant {:#04x}, SPI CS stuck?", first.unwrap_or(0));
+        }
+        !constant
+    }
 
-        Ok(())
+    // Soft reset for a chip stuck in a bad state: wake it, drop the transient state
+    // and rewrite the configuration from scratch, then check that it stuck
+    pub async fn reset_ltc(&mut self) -> Result<(), ()> {
+        self.wakeup().await;
+        Timer::after(Duration::from_millis(10)).await;
+
+        self.mode = MODE::NORMAL;
+        self.prev_mode = MODE::NORMAL;
+        self.manual_discharge = None;
+        self.stale_cells = 0;
+        self.stale_thermistors = 0;
+        self.temp_reads = 0;
+        self.die_temp = None;
+        self.last_vref = None;
+        self.update_timing = UpdateTiming::default();
+        self.init_cfg().await?;
+
+        if self.check_cs().await && self.config_readback_test().await {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    // Back from SLEEP the chip has its power-on configuration, REFON included: write ours again
+    // and give the reference time to come up before the next conversion
+    pub async fn wake_from_sleep(&mut self) {
+        self.wakeup().await;
+        let _ = self.init_cfg().await;
+        Timer::after(Duration::from_millis(10)).await;
     }
 
     pub async fn wakeup(&mut self) {
         let mut spi_data = self.spi.lock().await;
-        spi_data.cs.set_low();
+        spi_data.cs_low();
 
+        // A failed wake-up pulse shows up as a failed transfer right after, it is reported there
         for _ in 0..50 {
-            spi_data.write(&[0xff]).await;
+            let _ = spi_data.write(&[0xff]).await;
         }
 
-        spi_data.cs.set_high();
+        spi_data.cs_high();
         drop(spi_data);
     }
 
     pub async fn wakeup_idle(&mut self) {
         let mut spi_data = self.spi.lock().await;
-        spi_data.cs.set_low();
-        spi_data.write(&[0xFF; 8]).await;
-        spi_data.cs.set_high();
+        spi_data.cs_low();
+        // Like wakeup(), a failure is reported by the transfer that follows
+        let _ = spi_data.write(&[0xFF; 8]).await;
+        spi_data.cs_high();
         drop(spi_data);
     }
 
@@ -277,136 +707,156 @@ impl LTC6811 {
         self.wakeup_idle().await;
         let mut spi_data = self.spi.lock().await;
         // Send command
-        spi_data.write(&cmd_final).await;
+        spi_data.write(&cmd_final).await?;
         drop(spi_data);
         Ok(())
     }
 
     // Start cell voltage conversion
-    pub async fn start_cell_conversion(&mut self) -> Result<(), ()> {
-        let cmd = self.prepare_command(ADCV);
+    // ADCVSC rather than ADCV, so SC is sampled together with the cells for check_sum_of_cells
+    pub async fn start_cell_conversion(&mut self) -> Result<(), LtcError> {
+        self.start_conversion(ADCVSC).await
+    }
+
+    // Send an ADC command and poll until the conversion is done
+    async fn start_conversion(&mut self, cmd: [u8; 2]) -> Result<(), LtcError> {
+        let cmd = self.prepare_command(cmd);
 
         self.wakeup_idle().await;
         let mut spi_data = self.spi.lock().await;
         // Send command
-        spi_data.write(&cmd).await;
+        spi_data.write(&cmd).await.map_err(|_| LtcError::Read)?;
 
         drop(spi_data);
-        // Wait for conversion to complete (typical conversion time ~2ms)
+        self.poll_conversion(Duration::from_millis(1)).await
+    }
+
+    // Poll PLADC every `interval` until the conversion is done. SDO stuck low reads as a conversion
+    // that never ends: past ADC_POLL_TIMEOUT_MS it fails as a read, so ltc_function releases the
+    // lock and the failure counts towards FAULT_COMM
+    async fn poll_conversion(&mut self, interval: Duration) -> Result<(), LtcError> {
         let poll = self.prepare_command(PLADC);   // const PLADC: [u8;2] = [0x07, 0x00];
+        let deadline = Instant::now() + Duration::from_millis(ADC_POLL_TIMEOUT_MS);
         let mut status = [0u8; 8];
         loop {
             let mut spi_data = self.spi.lock().await;
-            spi_data.cmd_read(&poll, &mut status).await.unwrap();
+            spi_data.cmd_read(&poll, &mut status).await.map_err(|_| LtcError::Read)?;
+            drop(spi_data);
             if status[0] & 0x01 != 0 {
-                break; // conversion finished
+                return Ok(()); // conversion finished
             }
-            drop(spi_data);
-            embassy_time::Timer::after_millis(1).await;
+            if Instant::now() >= deadline {
+                defmt::error!("LTC6811 conversion still running after {}ms", ADC_POLL_TIMEOUT_MS);
+                return Err(LtcError::Read);
+            }
+            Timer::after(interval).await;
         }
-
-        Ok(())
     }
-    // Read cell voltage registers and update BMS
-    pub async fn read_cell_voltages(&mut self) -> Result<(), ()> {
+
+    // Convert and read the cell voltage registers without touching the BMS, for one-off
+    // reads by diagnostics and cross-checks as well as update(). History is not advanced.
+    // Each register group is PEC checked on its own, cells of a failed group come back None
+    // and are left in stale_cells. Err only when nothing at all could be read
+    pub async fn measure_cells(&mut self) -> Result<[Option<u16>; NUM_CELLS], LtcError> {
         // Start voltage conversion
         self.start_cell_conversion().await?;
 
-        self.wakeup_idle().await;
-        let mut spi_data = self.spi.lock().await;
-
-        // Read voltage registers (cells 1-3)
-        let cmd_a = self.prepare_command(RDCVA);
-        let mut data_a = [0u8; 8]; // 6 data bytes + 2 PEC bytes
-                                   // spi_data.write(&cmd_a).await;
-                                   // self.transfer_ltc(&mut spi_data, &mut data_a).await;
-        spi_data.cmd_read(&cmd_a, &mut data_a).await.unwrap();
-        // Read voltage registers (cells 4-6)
-        let cmd_b = self.prepare_command(RDCVB);
-        let mut data_b = [0u8; 8];
-        // spi_data.write(&cmd_b).await;
-        // self.transfer_ltc(&mut spi_data, &mut data_b).await;
-        spi_data.cmd_read(&cmd_b, &mut data_b).await.unwrap();
-
-        // Read voltage registers (cells 7-9)
-        let cmd_c = self.prepare_command(RDCVC);
-        let mut data_c = [0u8; 8];
-        // spi_data.write(&cmd_c).await;
-        // self.transfer_ltc(&mut spi_data, &mut data_c).await;
-        spi_data.cmd_read(&cmd_c, &mut data_c).await.unwrap();
-
-        // Read voltage registers (cells 10-12)
-        let cmd_d = self.prepare_command(RDCVD);
-        let mut data_d = [0u8; 8];
-        // spi_data.write(&cmd_d).await;
-        // self.transfer_ltc(&mut spi_data, &mut data_d).await;
-        spi_data.cmd_read(&cmd_d, &mut data_d).await.unwrap();
-
-        drop(spi_data);
-
-        // Process and update BMS with cell voltages
-        // Each cell voltage is 16-bit (2 bytes)
-
-        let mut cells: [u16; 12] = [0; 12];
-        // Cells 1-3
-        cells[0] = ((data_a[1] as u16) << 8) | (data_a[0] as u16);
-        cells[1] = ((data_a[3] as u16) << 8) | (data_a[2] as u16);
-        cells[2] = ((data_a[5] as u16) << 8) | (data_a[4] as u16);
-
-        // Cells 4-6
-        cells[3] = ((data_b[1] as u16) << 8) | (data_b[0] as u16);
-        cells[4] = ((data_b[3] as u16) << 8) | (data_b[2] as u16);
-        cells[5] = ((data_b[5] as u16) << 8) | (data_b[4] as u16);
-
-        // Cells 7-9
-        cells[6] = ((data_c[1] as u16) << 8) | (data_c[0] as u16);
-        cells[7] = ((data_c[3] as u16) << 8) | (data_c[2] as u16);
-        cells[8] = ((data_c[5] as u16) << 8) | (data_c[4] as u16);
-
-        // Cells 10-12
-        cells[9] = ((data_d[1] as u16) << 8) | (data_d[0] as u16);
-        cells[10] = ((data_d[3] as u16) << 8) | (data_d[2] as u16);
-        cells[11] = ((data_d[5] as u16) << 8) | (data_d[4] as u16);
-
-        // Update BMS with cell voltages
-        let mut bms_data = self.bms.lock().await;
-
-        for i in 0..12 {
-            bms_data.update_cell(i, cells[i]);
+        let mut cells = [None; NUM_CELLS];
+        self.stale_cells = 0;
+        for (group, cmd) in [RDCVA, RDCVB, RDCVC, RDCVD].into_iter().enumerate() {
+            match self.read_register_group(cmd).await {
+                Ok(data) => {
+                    for (i, code) in decode_group(&data).into_iter().enumerate() {
+                        cells[group * 3 + i] = Some(code);
+                    }
+                }
+                Err(_) => {
+                    defmt::error!("PEC fail cell group {}, cells {}-{} stale", group, group * 3 + 1, group * 3 + 3);
+                    self.stale_cells |= 0b111 << (group * 3);
+                }
+            }
         }
-        drop(bms_data);
-
-        Ok(())
+        if self.stale_cells == (1 << NUM_CELLS) - 1 {
+            return Err(LtcError::Read);
+        }
+        Ok(cells)
     }
 
-    pub async fn start_temperature_conversion(&mut self) -> Result<(), ()> {
+    pub async fn start_temperature_conversion(&mut self) -> Result<(), LtcError> {
         let cmd = self.prepare_command(ADAX);
         self.wakeup().await;
         let mut spi_data = self.spi.lock().await;
         // Send command
-        spi_data.write(&cmd).await;
+        spi_data.write(&cmd).await.map_err(|_| LtcError::Read)?;
         drop(spi_data);
 
         Timer::after_millis(1).await;
 
-        // Wait for conversion to complete (typical conversion time ~2ms)
-        let poll = self.prepare_command(PLADC);
-        loop {
-            let mut spi_data = self.spi.lock().await;
-            let mut status = [0u8; 8];
-            spi_data.cmd_read(&poll, &mut status).await.unwrap();
-            if status[0] & 0x01 != 0 { break }
-            drop(spi_data);
-            Timer::after(Duration::from_micros(500)).await;
-        }
+        self.poll_conversion(Duration::from_micros(500)).await?;
 
         Timer::after_millis(1).await;
 
         Ok(())
     }
 
-    // Read temperature sensor (assuming connected to GPIO1/AUX1)
-    pub async fn read_temperatures(&mut self) -> Result<(), ()> {
+    // Convert the GPIOs and return the parsed temperatures without touching the BMS.
+    // Each code is the average of TEMP_OVERSAMPLES conversions, a thermistor is stale only if all failed PEC
+    async fn measure_temperatures(&mut self) -> Result<[Option<DeciCelsius>; NUM_TERMISTORS], LtcError> {
+        let mut sums = [0u32; NUM_TERMISTORS];
+        let mut counts = [0u32; NUM_TERMISTORS];
+        for _ in 0..TEMP_OVERSAMPLES.max(1) {
+            let codes = self.read_temp_codes().await?;
+            for (i, code) in codes.iter().enumerate() {
+                if let Some(code) = code {
+                    sums[i] += *code as u32;
+                    counts[i] += 1;
+                }
+            }
+        }
+        self.temp_reads = self.temp_reads.saturating_add(1);
+
+        // Convert to temperatures, None for stale thermistors
+        let mut temps = [None; NUM_TERMISTORS];
+        self.stale_thermistors = 0;
+        for i in 0..NUM_TERMISTORS {
+            match (counts[i], self.last_vref) {
+                (1.., Some(voltage_ref)) => temps[i] = Some(self.parse_temp((sums[i] / counts[i]) as u16, voltage_ref)),
+                _ => self.stale_thermistors |= 1 << i,
+            }
+        }
+        Ok(temps)
+    }
+
+    // Raw thermistor codes of one conversion, one per mux step. Thermistor i is the i-th analog
+    // input of step 0, then of step 1 and so on
+    async fn read_temp_codes(&mut self) -> Result<[Option<u16>; NUM_TERMISTORS], LtcError> {
+        let mut codes = [None; NUM_TERMISTORS];
+        match self.thermistor_mux {
+            None => {
+                let gpios = self.convert_gpios().await?;
+                for (code, gpio) in codes.iter_mut().zip(gpios) {
+                    *code = gpio;
+                }
+            }
+            Some(mux) => {
+                let mut next = 0;
+                for step in 0..mux.steps {
+                    self.select_mux_step(&mux, step).await.map_err(|_| LtcError::Read)?;
+                    let gpios = self.convert_gpios().await?;
+                    for (gpio, &code) in gpios.iter().enumerate() {
+                        if mux.analog & (1 << gpio) != 0 && next < NUM_TERMISTORS {
+                            codes[next] = code;
+                            next += 1;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(codes)
+    }
+
+    // Convert and read GPIO1-4, None for the GPIOs of a register group that failed PEC
+    async fn convert_gpios(&mut self) -> Result<[Option<u16>; 4], LtcError> {
         // 1) start the ADC on the GPIO pins
         self.start_temperature_conversion().await?;
 
@@ -416,72 +866,343 @@ impl LTC6811 {
         // lock SPI once
         let mut auxa = [0u8; 8];
         let cmd_a = self.prepare_command(RDAUXA);
-        spi_data.cmd_read(&cmd_a, &mut auxa).await.unwrap();
+        spi_data.cmd_read(&cmd_a, &mut auxa).await.map_err(|_| LtcError::Read)?;
 
         // 3) read AUXB (contains GPIO4)
         let mut auxb = [0u8; 8];
         let cmd_b = self.prepare_command(RDAUXB);
-        spi_data.cmd_read(&cmd_b, &mut auxb).await.unwrap();
+        spi_data.cmd_read(&cmd_b, &mut auxb).await.map_err(|_| LtcError::Read)?;
         // release SPI
         drop(spi_data);
 
-        // 4) PEC check
-        let pec_a = [auxa[6], auxa[7]];
-        if pec_a != self.calculate_pec(&auxa[0..6]) {
-            defmt::error!("PEC fail AUXA");
-            //return Err(());
+        // 4) PEC check, a failed group only makes its own thermistors stale
+        let aux_a_ok = [auxa[6], auxa[7]] == self.calculate_pec(&auxa[0..6]);
+        if !aux_a_ok {
+            DIAGNOSTICS.bump(Counter::PecError);
+            defmt::error!("PEC fail AUXA, GPIO1-3 stale");
+        }
+        let aux_b_ok = [auxb[6], auxb[7]] == self.calculate_pec(&auxb[0..6]);
+        if !aux_b_ok {
+            DIAGNOSTICS.bump(Counter::PecError);
+            defmt::error!("PEC fail AUXB, GPIO4 stale");
         }
-        let pec_b = [auxb[6], auxb[7]];
-        if pec_b != self.calculate_pec(&auxb[0..6]) {
-            defmt::error!("PEC fail AUXB");
-            //return Err(());
+
+        // VREF2 comes with AUXB, GPIO1-3 can still be converted with the last good one
+        if aux_b_ok {
+            self.last_vref = Some(u16::from_be_bytes([auxb[5], auxb[4]]));
         }
 
         // 5) extract the four raw ADC codes
-        let codes = [
-            u16::from_be_bytes([auxa[1], auxa[0]]), // GPIO1
-            u16::from_be_bytes([auxa[3], auxa[2]]), // GPIO2
-            u16::from_be_bytes([auxa[5], auxa[4]]), // GPIO3
-            u16::from_be_bytes([auxb[1], auxb[0]]), // GPIO4
-        ];
+        Ok([
+            aux_a_ok.then(|| u16::from_be_bytes([auxa[1], auxa[0]])), // GPIO1
+            aux_a_ok.then(|| u16::from_be_bytes([auxa[3], auxa[2]])), // GPIO2
+            aux_a_ok.then(|| u16::from_be_bytes([auxa[5], auxa[4]])), // GPIO3
+            aux_b_ok.then(|| u16::from_be_bytes([auxb[1], auxb[0]])), // GPIO4
+        ])
+    }
+
+    // Drive the mux select GPIOs with the bits of `step`, LSB on the lowest select GPIO.
+    // A GPIO bit of 1 releases the pull-down, so the line goes high through the board pull-up
+    async fn select_mux_step(&mut self, mux: &ThermistorMux, step: u8) -> Result<(), ()> {
+        let mut gpio_bits = GPIOS >> 3;
+        let mut bit = 0;
+        for gpio in 0..5 {
+            if mux.select & (1 << gpio) != 0 {
+                if step & (1 << bit) != 0 {
+                    gpio_bits |= 1 << gpio;
+                } else {
+                    gpio_bits &= !(1 << gpio);
+                }
+                bit += 1;
+            }
+        }
+        self.config[0] = (self.config[0] & 0x07) | (gpio_bits << 3);
+        self.write_config().await?;
+        Timer::after_millis(MUX_SETTLE_MS).await;
+        Ok(())
+    }
+
+    // Read a register group and verify its PEC
+    async fn read_register_group(&mut self, cmd: [u8; 2]) -> Result<[u8; 6], ()> {
+        let cmd = self.prepare_command(cmd);
+        let mut data = [0u8; 8]; // 6 data bytes + 2 PEC bytes
+
+        self.wakeup_idle().await;
+        let mut spi_data = self.spi.lock().await;
+        spi_data.cmd_read(&cmd, &mut data).await?;
+        drop(spi_data);
+
+        if [data[6], data[7]] != self.calculate_pec(&data[0..6]) {
+            DIAGNOSTICS.bump(Counter::PecError);
+            return Err(());
+        }
+
+        let mut group = [0u8; 6];
+        group.copy_from_slice(&data[0..6]);
+        Ok(group)
+    }
+
+    // Bench passthrough: any 2 byte command with its PEC, then 8 bytes clocked back whatever the
+    // command is. Returns the 6 data bytes, the PEC received and the PEC computed over the data.
+    // A write command goes out with 0xFF data and a wrong PEC, so the chip drops it
+    #[cfg_attr(not(feature = "spi-passthrough"), allow(unused))]
+    pub async fn raw_cmd_read(&mut self, cmd: [u8; 2]) -> Result<([u8; 6], [u8; 2], [u8; 2]), ()> {
+        let cmd = self.prepare_command(cmd);
+        let mut data = [0u8; 8];
+
+        self.wakeup_idle().await;
+        let mut spi_data = self.spi.lock().await;
+        spi_data.cmd_read(&cmd, &mut data).await?;
+        drop(spi_data);
+
+        let mut group = [0u8; 6];
+        group.copy_from_slice(&data[0..6]);
+        Ok((group, [data[6], data[7]], self.calculate_pec(&group)))
+    }
+
+    // Read all four cell voltage register groups, PEC checked
+    async fn read_cell_codes(&mut self) -> Result<[u16; NUM_CELLS], ()> {
+        let mut cells = [0u16; NUM_CELLS];
+        for (group, cmd) in [RDCVA, RDCVB, RDCVC, RDCVD].into_iter().enumerate() {
+            let data = self.read_register_group(cmd).await?;
+            cells[group * 3..group * 3 + 3].copy_from_slice(&decode_group(&data));
+        }
+        Ok(cells)
+    }
+
+    // Convert and read back a single cell (0-based), only its register group is read
+    #[allow(unused)]
+    pub async fn read_cell(&mut self, index: usize) -> Result<u16, ()> {
+        if index >= NUM_CELLS {
+            return Err(());
+        }
+
+        self.start_cell_conversion().await.map_err(|_| ())?;
+        let group = [RDCVA, RDCVB, RDCVC, RDCVD][index / 3];
+        let data = self.read_register_group(group).await?;
+        Ok(decode_group(&data)[index % 3])
+    }
+
+    // Run all the chip self tests: CVST, AXST, open wire and config readback
+    pub async fn run_diagnostics(&mut self) -> DiagnosticReport {
+        let mut report = DiagnosticReport {
+            cell_test: self.cell_self_test().await,
+            aux_test: self.aux_self_test().await,
+            ..Default::default()
+        };
+
+        match self.open_wire_test().await {
+            Ok(open_wire) => {
+                report.open_wire_test = open_wire == 0;
+                report.open_wire = open_wire;
+            }
+            Err(_) => report.open_wire_test = false,
+        }
+
+        report.config_test = self.config_readback_test().await;
 
-        let voltage_ref = u16::from_be_bytes([auxb[5], auxb[4]]);
+        report.status_test = match self.read_status_b().await {
+            Ok(status) => {
+                defmt::info!(
+                    "LTC6811 rev {} VD {} UV {:#05x} OV {:#05x}",
+                    status.revision, status.vd, status.cell_uv, status.cell_ov
+                );
+                status.vd_in_range() && !status.mux_fail && !status.thermal_shutdown
+            }
+            Err(_) => false,
+        };
+
+        if !report.passed() {
+            defmt::error!(
+                "LTC6811 diagnostics failed: CVST {} AXST {} OW {:#06x} CFG {} STAT {}",
+                report.cell_test, report.aux_test, report.open_wire, report.config_test, report.status_test
+            );
+        }
+
+        report
+    }
+
+    async fn cell_self_test(&mut self) -> bool {
+        if self.start_conversion(CVST).await.is_err() {
+            return false;
+        }
+        match self.read_cell_codes().await {
+            Ok(cells) => cells.iter().all(|&code| code == SELF_TEST_PATTERN),
+            Err(_) => false,
+        }
+    }
+
+    async fn aux_self_test(&mut self) -> bool {
+        if self.start_conversion(AXST).await.is_err() {
+            return false;
+        }
+        // AUXA holds GPIO1-3, AUXB holds GPIO4-5 and REF
+        match (self.read_register_group(RDAUXA).await, self.read_register_group(RDAUXB).await) {
+            (Ok(auxa), Ok(auxb)) => decode_group(&auxa)
+                .iter()
+                .chain(decode_group(&auxb).iter())
+                .all(|&code| code == SELF_TEST_PATTERN),
+            _ => false,
+        }
+    }
+
+    // Returns a bitmap of the open C(n) inputs, see "Open Wire Check" in the datasheet
+    async fn open_wire_test(&mut self) -> Result<u16, ()> {
+        // At least two ADOW conversions are needed for each current direction
+        for _ in 0..2 {
+            self.start_conversion(ADOW_PUP).await.map_err(|_| ())?;
+        }
+        let pull_up = self.read_cell_codes().await?;
 
-        // 6) update your BMS struct
-        let mut bms = self.bms.lock().await;
-        for (i, &code) in codes.iter().enumerate() {
-            bms.update_temp(i, self.parse_temp(code, voltage_ref));
+        for _ in 0..2 {
+            self.start_conversion(ADOW_PDN).await.map_err(|_| ())?;
+        }
+        let pull_down = self.read_cell_codes().await?;
+
+        let mut open_wire: u16 = 0;
+        if pull_up[0] == 0 {
+            open_wire |= 1;
+        }
+        for i in 1..NUM_CELLS {
+            // Cell i+1 dropping by more than 400mV means C(i) is open
+            if (pull_up[i] as i32 - pull_down[i] as i32) < OPEN_WIRE_THRESHOLD {
+                open_wire |= 1 << i;
+            }
+        }
+        if pull_down[NUM_CELLS - 1] == 0 {
+            open_wire |= 1 << NUM_CELLS;
+        }
+
+        Ok(open_wire)
+    }
+
+    // Interactive diagnostic: read CFGR back and print it next to what we meant to write.
+    // GPIO bits of CFGR0 read back the pin level, so they can differ without a fault
+    pub async fn dump_config(&mut self) -> Result<(), ()> {
+        self.wakeup().await;
+        let intended = self.config;
+        match self.read_register_group(RDCFGA).await {
+            Ok(read) => {
+                defmt::info!("CFGR  intended  read");
+                for i in 0..intended.len() {
+                    defmt::info!(
+                        "CFGR{}  {:#04x}      {:#04x}{}",
+                        i,
+                        intended[i],
+                        read[i],
+                        if intended[i] != read[i] {"  *"} else {""}
+                    );
+                }
+                Ok(())
+            }
+            Err(_) => {
+                defmt::error!("RDCFGA failed PEC, intended CFGR {=[u8]:#04x}", intended);
+                Err(())
+            }
+        }
+    }
+
+    async fn config_readback_test(&mut self) -> bool {
+        match self.read_register_group(RDCFGA).await {
+            Ok(read) => {
+                (read[0] & CFGR0_READBACK_MASK) == (self.config[0] & CFGR0_READBACK_MASK)
+                    && read[1..] == self.config[1..]
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Convert the status group and read back Status Register Group B.
+    // The UV/OV flags reflect the last cell conversion against the VUV/VOV set in init_cfg.
+    pub async fn read_status_b(&mut self) -> Result<StatusB, ()> {
+        self.start_conversion(ADSTAT).await.map_err(|_| ())?;
+        let data = self.read_register_group(RDSTATB).await?;
+        Ok(StatusB::from_register(&data))
+    }
+
+    // Read the per-cell (UV, OV) flags the chip set during the last cell conversion.
+    // No ADSTAT needed, the comparison against VUV/VOV runs with every ADCV.
+    pub async fn read_voltage_flags(&mut self) -> Result<(u16, u16), ()> {
+        let data = self.read_register_group(RDSTATB).await?;
+        let status = StatusB::from_register(&data);
+        Ok((status.cell_uv, status.cell_ov))
+    }
+
+    // Sum of cells from the last ADCVSC, in 100uV
+    pub async fn read_sum_of_cells(&mut self) -> Result<u32, ()> {
+        let data = self.read_register_group(RDSTATA).await?;
+        Ok(decode_group(&data)[0] as u32 * 20) // SC LSB is 20 cell LSBs
+    }
+
+    // Die temperature from ITMP, converted with ADSTAT. ITMP is 7.5mV/K in 100uV steps,
+    // T = ITMP / 75 - 273 °C. Below 0°C reads as 0 like the thermistors
+    pub async fn read_die_temp(&mut self) -> Result<DeciCelsius, ()> {
+        self.start_conversion(ADSTAT).await.map_err(|_| ())?;
+        let data = self.read_register_group(RDSTATA).await?;
+        let itmp = decode_group(&data)[1] as u32;
+        Ok(DeciCelsius::new((itmp * 2 / 15).saturating_sub(2730).min(u16::MAX as u32) as u16))
+    }
+
+    // A per-cell read corrupted past PEC shows up as a gap between our sum and the chip's SC
+    async fn check_sum_of_cells(&mut self, cells: &[u16; NUM_CELLS]) -> Result<(), LtcError> {
+        let sc = self.read_sum_of_cells().await.map_err(|_| LtcError::Read)?;
+        let sum: u32 = cells.iter().map(|&cell| cell as u32).sum();
+        if sum.abs_diff(sc) > self.sc_tolerance {
+            defmt::warn!("Sum of cells {} differs from SC {} (100uV)", sum, sc);
+            return Err(LtcError::SumOfCells);
         }
-        drop(bms);
         Ok(())
     }
 
     // Periodic update - call this regularly to keep BMS data fresh
-    pub async fn update(&mut self) -> Result<(), ()> {
+    pub async fn update(&mut self) -> Result<(), LtcError> {
         // Read all cell voltages
+        let discharging = self.discharge_bitmap() != 0;
         self.set_mode(MODE::NORMAL).await;
+        // Only when the bits were really muted, a manual discharge stays on while measuring
+        if discharging && self.discharge_bitmap() == 0 {
+            Timer::after_millis(BAL_SETTLE_MS).await;
+        }
 
-        match self.read_cell_voltages().await {
-            Ok(_) => {}
-            Err(_) => return Err(()),
+        // Both readings are taken before anything is written, so a failure leaves the
+        // current history slot and the aggregates untouched
+        let cells = self.measure_cells().await?;
+
+        // A partially corrupt read would otherwise land in the history and look like an over-voltage
+        let implausible = cells
+            .iter()
+            .enumerate()
+            .filter(|(_, &cell)| cell.is_some_and(|cell| cell > IMPLAUSIBLE_VOLTAGE))
+            .fold(0u16, |bitmap, (i, _)| bitmap | (1 << i));
+        if implausible != 0 {
+            return Err(LtcError::ImplausibleCell(implausible));
         }
-        
-        match self.read_temperatures().await {
-            Ok(_) => {},
-            Err(_) => return Err(()),
+        // SC covers all twelve cells, it can only be compared when every group read cleanly
+        if self.stale_cells == 0 {
+            self.check_sum_of_cells(&cells.map(|cell| cell.unwrap_or(0))).await?;
         }
+        let temps = self.measure_temperatures().await?;
+        // Only a plausibility reference for the thermistors, a failed read is not an update failure.
+        // After the sum of cells check, ADSTAT overwrites SC
+        self.die_temp = self.read_die_temp().await.ok();
 
+        // One history slot per cycle, filled and committed in one critical section
         let mut bms_data = self.bms.lock().await;
-        bms_data.update();
+        bms_data.push_reading(&cells.map(|cell| cell.map(DeciMilliVolt::new)), &temps, Instant::now().as_millis());
+        bms_data.update_temp_rate(&temps.map(|temp| temp.map_or(u16::MAX, DeciCelsius::into_raw)), Instant::now().as_millis());
         drop(bms_data);
 
         Ok(())
     }
 
 
-    pub fn parse_temp(&self, voltage_gpio: u16, _voltage_ref: u16) -> u16 {
+    pub fn parse_temp(&self, voltage_gpio: u16, _voltage_ref: u16) -> DeciCelsius {
         if voltage_gpio == 0 {
-            return u16::MAX;
+            return DeciCelsius::new(u16::MAX);
+        }
+
+        if let Some(table) = THERMISTOR_TABLE {
+            return self.parse_temp_table(voltage_gpio, _voltage_ref, table);
         }
 
         let r_th = (RTHERMISTOR_OHM as f32)* (voltage_gpio as f32)*0.1 / ((_voltage_ref as f32)*0.1 - (((voltage_gpio as f32) * 0.1))); 
@@ -490,33 +1211,239 @@ impl LTC6811 {
         
 
         if inv_t < 0.0f32 {
-            return u16::MIN;
+            return DeciCelsius::new(u16::MIN);
         }
 
         let temp = if inv_t != 0.0f32 {1.0f32/inv_t} else {1.0f32/(inv_t+ 1e-6)};
 
         let temp_i32: i32 = roundf((temp - KELVIN_2_CELSIUS)*10.0f32) as i32;
         if temp_i32 < (MIN_TEMP as i32) {
-            return MIN_TEMP;
+            DeciCelsius::new(MIN_TEMP)
         } else if temp_i32 > (MAX_TEMP as i32) {
-            return MAX_TEMP;
+            DeciCelsius::new(MAX_TEMP)
         } else {
-            temp_i32 as u16
+            DeciCelsius::new(temp_i32 as u16)
         }       
     }
 
-    pub async fn check_need_balance(&self) -> bool {
+    // Table alternative to the Beta model, see THERMISTOR_TABLE. The GPIO code is rescaled to the
+    // nominal VREF2 first, so the table stays ratiometric like the model
+    pub fn parse_temp_table(&self, voltage_gpio: u16, voltage_ref: u16, table: &[(u16, i16)]) -> DeciCelsius {
+        if voltage_ref == 0 || table.is_empty() {
+            return DeciCelsius::new(MAX_TEMP);
+        }
+        let code = (voltage_gpio as u32 * VREF2_NOMINAL as u32 / voltage_ref as u32).min(u16::MAX as u32) as u16;
+
+        let temp: i32 = match table.iter().position(|&(entry_code, _)| entry_code >= code) {
+            Some(0) => table[0].1 as i32,
+            Some(i) => {
+                let (code_lo, temp_lo) = table[i - 1];
+                let (code_hi, temp_hi) = table[i];
+                let span = (code_hi - code_lo) as i32;
+                if span == 0 {
+                    temp_hi as i32
+                } else {
+                    temp_lo as i32 + (temp_hi as i32 - temp_lo as i32) * (code - code_lo) as i32 / span
+                }
+            }
+            None => table[table.len() - 1].1 as i32,
+        };
+
+        DeciCelsius::new(temp.clamp(MIN_TEMP as i32, MAX_TEMP as i32) as u16)
+    }
+
+    // Whether the cell voltages can be judged for balancing: the pack has rested, or charged below
+    // BAL_CHARGE_CURRENT, for REST_SETTLE_MS (RestGate). ltc_function asks before check_need_balance
+    pub async fn voltages_settled(&mut self) -> bool {
+        let current = self.bms.lock().await.current();
+        self.rest_gate.update(current, Instant::now().as_millis())
+    }
+
+    // Balancing stop condition for ltc_function: true while some cell is more than the balancing
+    // epsilon (BAL_EPSILON, BAL_EPSILON_WARM on a warm pack) above min_volt, compared signed in i32.
+    // Same rule as the bitmap init_cfg writes, both go through compute_discharge_bitmap. Only
+    // meaningful once voltages_settled()
+    pub async fn check_need_balance(&mut self) -> bool {
         let bms_data = self.bms.lock().await;
-        // Iterate over all 12 cells. Here we assume that bms_data.cell_volts is an array of 12 u16.
+        self.compute_discharge_bitmap(&bms_data) != 0
+    }
+
+    // Cells to discharge: every wired cell above the lowest one by more than the balancing epsilon
+    fn compute_discharge_bitmap(&self, bms_data: &SLAVEBMS) -> u16 {
+        let cell_mask = bms_data.topology().cell_mask();
+        let epsilon = self.balance_config.epsilon(bms_data.max_temp());
+        let mut discharge_bitmap: u16 = 0;
         for i in 0..NUM_CELLS {
-            // If the cell voltage exceeds the minimum by more than BAL_EPSILON, enable discharge.
-            if (bms_data.cell_volts(i) as i16 - bms_data.min_volt() as i16)
-                > BAL_EPSILON
-            {
-                return true;
+            // Cell codes go up to ~42000, past i16::MAX, so compare in i32.
+            // A cell below the (averaged) minimum gives a negative delta and is never discharged.
+            if (bms_data.cell_volts(i).into_raw() as i32 - bms_data.min_volt().into_raw() as i32) > epsilon as i32 {
+                discharge_bitmap |= 1 << i;
             }
         }
-        false
+        discharge_bitmap & cell_mask
+    }
+
+    // Raw CFGR0..CFGR5 as last written
+    #[allow(unused)]
+    pub fn config_registers(&self) -> [u8; 6] {
+        self.config
+    }
+
+    // Escape hatch for experiments: writes one CFGR byte as is. It bypasses the managed
+    // fields, and the next init_cfg (every set_mode) overwrites it again
+    #[allow(unused)]
+    pub async fn set_config_register(&mut self, index: usize, value: u8) -> Result<(), ()> {
+        if index >= self.config.len() {
+            return Err(());
+        }
+        defmt::warn!("CFGR{} set to {:#04x} by hand, bypassing managed config", index, value);
+        self.config[index] = value;
+        self.write_config().await
+    }
+
+    // Discharge bits as last written to CFGR4 (cells 1-8) and CFGR5 (cells 9-12)
+    pub fn discharge_bitmap(&self) -> u16 {
+        (self.config[4] as u16) | (((self.config[5] & 0x0F) as u16) << 8)
+    }
+
+    pub fn manual_discharge(&self) -> Option<u16> {
+        self.manual_discharge
+    }
+
+    // Bleed exactly the cells in `bitmap` (12 bits) until cleared, bypassing the balancing
+    // algorithm. The caller is in charge of clearing it on faults
+    pub async fn set_manual_discharge(&mut self, bitmap: u16) -> Result<(), ()> {
+        self.manual_discharge = Some(bitmap & 0x0FFF);
+        self.init_cfg().await
+    }
+
+    pub async fn clear_manual_discharge(&mut self) -> Result<(), ()> {
+        self.manual_discharge = None;
+        self.init_cfg().await
+    }
+
+    // Charge the time since the last write to the cells whose discharge bit was set by it
+    fn accumulate_balance_time(&mut self, now_ms: u64) {
+        if let Some(last) = self.last_cfg_ms {
+            let elapsed = (now_ms - last).min(u32::MAX as u64) as u32;
+            let bitmap = self.discharge_bitmap();
+            for i in 0..NUM_CELLS {
+                if bitmap & (1 << i) != 0 {
+                    self.balance_time_ms[i] = self.balance_time_ms[i].saturating_add(elapsed);
+                }
+            }
+        }
+        self.last_cfg_ms = Some(now_ms);
+    }
+
+    // Accumulated discharge time per cell in ms, includes manual discharge
+    pub fn balance_time_ms(&self) -> [u32; NUM_CELLS] {
+        self.balance_time_ms
+    }
+
+    pub fn reset_balance_time(&mut self) {
+        self.balance_time_ms = [0; NUM_CELLS];
+    }
+
+    // Bitmap the balancing logic asked for, equal to discharge_bitmap() unless in dry run
+    pub fn planned_bitmap(&self) -> u16 {
+        self.planned_bitmap
+    }
+
+    pub fn balancing_state(&self) -> BalancingState {
+        self.balancing_state
+    }
+
+    // Back to NORMAL mode, recording why balancing ended
+    pub async fn stop_balancing(&mut self, reason: BalancingState) {
+        if self.balancing_state != reason {
+            defmt::info!("Balancing state {} -> {}", self.balancing_state, reason);
+        }
+        self.balancing_state = reason;
+        self.set_mode(MODE::NORMAL).await;
+    }
+
+    pub fn balance_dry_run(&self) -> bool {
+        self.balance_dry_run
+    }
+
+    pub fn set_balance_dry_run(&mut self, dry_run: bool) {
+        self.balance_dry_run = dry_run;
+    }
+
+    fn log_dry_run(&self, bms_data: &SLAVEBMS, discharge_bitmap: u16) {
+        defmt::info!("Balancing dry run, bitmap {:#05x}", discharge_bitmap);
+        for i in 0..NUM_CELLS {
+            defmt::info!(
+                "Cell {}: +{} from min, {}",
+                i,
+                bms_data.cell_volts(i).into_raw() as i32 - bms_data.min_volt().into_raw() as i32,
+                if discharge_bitmap & (1 << i) != 0 {"DISCHARGE"} else {"-"}
+            );
+        }
+    }
+
+    pub fn _fet_hold(&self) -> FetHold {
+        self.fet_hold
+    }
+
+    pub fn _set_fet_hold(&mut self, min_on_ms: u64, cooldown_ms: u64) {
+        self.fet_hold.min_on_ms = min_on_ms;
+        self.fet_hold.cooldown_ms = cooldown_ms;
+    }
+
+    pub fn _rest_gate(&self) -> RestGate {
+        self.rest_gate
+    }
+
+    pub fn _set_rest_gate(&mut self, threshold: DeciMilliAmp, settle_ms: u64) {
+        self.rest_gate.threshold = threshold;
+        self.rest_gate.settle_ms = settle_ms;
+    }
+
+    pub fn update_timing(&self) -> UpdateTiming {
+        self.update_timing
+    }
+
+    pub fn record_update_time(&mut self, us: u32) {
+        self.update_timing.record(us);
+    }
+
+    pub fn stale_cells(&self) -> u16 {
+        self.stale_cells
+    }
+
+    pub fn stale_thermistors(&self) -> u16 {
+        self.stale_thermistors
+    }
+
+    // Past the TEMP_WARMUP_READS power-on readings, temperature faults can be trusted
+    pub fn temps_settled(&self) -> bool {
+        self.temp_reads >= TEMP_WARMUP_READS
+    }
+
+    pub fn cs_fault(&self) -> bool {
+        self.cs_fault
+    }
+
+    pub fn die_temp(&self) -> Option<DeciCelsius> {
+        self.die_temp
+    }
+
+    pub fn _sc_tolerance(&self) -> u32 {
+        self.sc_tolerance
+    }
+
+    pub fn _set_sc_tolerance(&mut self, sc_tolerance: u32) {
+        self.sc_tolerance = sc_tolerance;
+    }
+
+    pub fn balance_config(&self) -> BalanceConfig {
+        self.balance_config
+    }
+
+    pub fn _set_balance_config(&mut self, balance_config: BalanceConfig) {
+        self.balance_config = balance_config;
     }
 
     // pub async fn wait_poll(&mut self) {
@@ -533,3 +1460,12 @@ impl LTC6811 {
     // }
 
 }
+
+// Split a 6 byte register group into its three little endian 16-bit codes
+fn decode_group(data: &[u8; 6]) -> [u16; 3] {
+    [
+        u16::from_le_bytes([data[0], data[1]]),
+        u16::from_le_bytes([data[2], data[3]]),
+        u16::from_le_bytes([data[4], data[5]]),
+    ]
+}
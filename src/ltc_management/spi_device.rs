@@ -1,94 +1,48 @@
-use embassy_stm32::gpio::{Level, Output, Pin, Speed};
-use embassy_stm32::mode::Async;
-use embassy_stm32::spi::{BitOrder, Config, Instance, MisoPin, MosiPin, RxDma, SckPin, Spi, TxDma, MODE_3};
-use embassy_stm32::time::Hertz;
-use embassy_stm32::Peripheral;
+use embedded_hal_1::digital::OutputPin;
+use embedded_hal_async::spi::SpiBus;
 
-pub struct SpiDevice<'a> {
-    spi: Option<Spi<'a, Async>>,
-    pub cs: Output<'a>
+// SPI transport for the LTC6811: any async SpiBus plus a manually driven CS pin.
+// The STM32 bus is built in board. It is owned from construction on, so every method can use it:
+// failures only come from the bus, every transfer returns them as Err(()) and releases CS first
+pub struct SpiDevice<SPI, CS> {
+    spi: SPI,
+    cs: CS
 }
 
-impl<'a> SpiDevice<'a> {
-    pub async fn new<T: Instance>(
-        peri: (impl Peripheral<P = T> + 'a), 
-        sck: (impl Peripheral<P = impl SckPin<T>> + 'a), 
-        mosi: (impl Peripheral<P = impl MosiPin<T>> + 'a), 
-        miso: (impl Peripheral<P = impl MisoPin<T>> + 'a), 
-        cs:  (impl Peripheral<P = impl Pin> + 'a),
-        tx_dma: (impl Peripheral<P = impl TxDma<T>> + 'a),
-        rx_dma: (impl Peripheral<P = impl RxDma<T>> + 'a),
-    ) -> Self {
+impl<SPI: SpiBus, CS: OutputPin> SpiDevice<SPI, CS> {
+    // Wrap a SpiBus/OutputPin pair, the CS pin already at its idle high level
+    pub fn from_parts(spi: SPI, cs: CS) -> Self {
+        SpiDevice { spi, cs }
+    }
 
-        let mut spi_config = Config::default();
-        spi_config.mode = MODE_3;
-        spi_config.bit_order = BitOrder::MsbFirst;
-        spi_config.frequency = Hertz(1_000_000);
-        
-        
-        let spi = Spi::new(
-            peri,
-            sck,
-            mosi,
-            miso,
-            tx_dma,
-            rx_dma,
-            spi_config
-        );
-        
-        let spi = SpiDevice { 
-            spi: Some(spi),
-            cs: Output::new(cs, Level::High, Speed::VeryHigh)
-        };
+    // CS errors are ignored: on every pin we use setting a level is infallible
+    pub fn cs_low(&mut self) {
+        let _ = self.cs.set_low();
+    }
 
-        spi
+    pub fn cs_high(&mut self) {
+        let _ = self.cs.set_high();
     }
 
-    pub async fn write(&mut self, data: &[u8]) {
-        if let Some(spi) = self.spi.as_mut() {
-            self.cs.set_low();
-    
-            if let Err(e) = spi.write(data).await {
-                defmt::error!("SPI write failed: {:?}", e);
-            }
-            self.cs.set_high();
-        } else {
-            return;
-        }
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+        let _ = self.cs.set_low();
+        let result = self.spi.write(data).await.map_err(|_| ());
+        let _ = self.cs.set_high();
+        result
     }
-    
-    pub async fn _read(&mut self, buffer: &mut [u8]) {
-        // Ensure spi is initialized before using
-        if let Some(spi) = self.spi.as_mut() {
-            self.cs.set_low();
-    
-            if let Err(e) = spi.read(buffer).await {
-                defmt::error!("SPI read failed: {:?}", e);
-            }
 
-            self.cs.set_high();
-        } else {
-            return;
-        }
+    pub async fn _read(&mut self, buffer: &mut [u8]) -> Result<(), ()> {
+        let _ = self.cs.set_low();
+        let result = self.spi.read(buffer).await.map_err(|_| ());
+        let _ = self.cs.set_high();
+        result
     }
 
     pub async fn _transfer(&mut self, tx_buffer: &[u8], rx_buffer: &mut [u8]) -> Result<(), ()> {
-        // Ensure spi is initialized before using
-        if let Some(spi) = self.spi.as_mut() {
-            self.cs.set_low();
-            match spi.transfer(rx_buffer, tx_buffer).await {
-                Ok(_) => {
-                    self.cs.set_high();
-                    return Ok(())
-                }
-                Err(_) => {
-                    self.cs.set_high();
-                    return Err(());
-                }
-            }
-        } else {
-            return Err(());
-        }
+        let _ = self.cs.set_low();
+        let result = self.spi.transfer(rx_buffer, tx_buffer).await.map_err(|_| ());
+        let _ = self.cs.set_high();
+        result
     }
 
     pub async fn cmd_read(
@@ -96,22 +50,22 @@ impl<'a> SpiDevice<'a> {
         cmd: &[u8;4],
         resp: &mut [u8;8],
     ) -> Result<(), ()> {
-        // take the inner Spi rather than using write()/transfer()
-        let spi = self.spi.as_mut().unwrap();
+        // use the bus directly rather than write()/transfer(), CS stays low across both
+        let spi = &mut self.spi;
 
         // 1) CS low once
-        self.cs.set_low();
+        let _ = self.cs.set_low();
 
-        // 2) send the 4-byte command
-        spi.write(cmd).await.map_err(|_| ())?;
-
-        // 3) clock out 8 dummy bytes and capture the response
+        // 2) send the 4-byte command, 3) clock out 8 dummy bytes and capture the response
         let tx = [0xFFu8; 8];
-        spi.transfer(resp, &tx).await.map_err(|_| ())?;
+        let result = match spi.write(cmd).await {
+            Ok(()) => spi.transfer(resp, &tx).await,
+            Err(e) => Err(e),
+        };
 
-        // 4) CS high
-        self.cs.set_high();
+        // 4) CS high, on a failed transfer too
+        let _ = self.cs.set_high();
 
-        Ok(())
+        result.map_err(|_| ())
     }
 }
\ No newline at end of file
@@ -1,64 +1,217 @@
 #![no_std]
 #![no_main]
 
-use libm::roundf;
+#[cfg(all(feature = "fault-injection", not(debug_assertions)))]
+compile_error!("fault-injection forces fake faults on the bus, it is only allowed in debug builds");
+
 use embassy_executor::Spawner;
 use embassy_stm32::gpio::{Level, Output, Speed};
 use embassy_stm32::adc::{Adc, Resolution};
+use embassy_stm32::can::enums::BusError;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
 use static_cell::StaticCell;
-use embassy_stm32::peripherals::ADC1;
+use embassy_futures::select::select;
+use embassy_stm32::peripherals::{ADC1, ADC2};
 
 
 use crate::usb_serial::usb::Serial;
-use crate::{can_management::{CanError, CanFrame}, ltc_management::ltc6811::MODE};
+use crate::{can_management::CanError, ltc_management::ltc6811::{BalancingState, LtcError, MODE}};
 
 use defmt::info;
-// use panic_probe as _;
+#[cfg(feature = "rtt-log")]
+use defmt_rtt as _;
+#[cfg(feature = "rtt-log")]
+use panic_probe as _;
 
+mod config;
 mod types;
 mod can_management;
 mod ltc_management;
 mod usb_serial;
+mod backup;
+mod board;
 
-use types::{CanMsg, VOLTAGES, SLAVEBMS, TEMPERATURES};
-use can_management::{can_operation, can_operation_tech, CanController};
-use ltc_management::{SpiDevice, LTC6811};
+use types::{CanMsg, DeciCelsius, DeciMilliAmp, DeciMilliVolt, VOLTAGES, SLAVEBMS, TEMPERATURES};
+use types::fault::*;
+use types::diagnostics::{Counter, DIAGNOSTICS};
+use types::bms::{BmsSnapshot, CurrentDirection, Topology, NUM_CELLS, NUM_TERMISTORS};
+use types::scaling::{adc_to_mv, current_from_sensor_mv, pack_from_pin_mv};
+use can_management::{can_balance_status, can_cell_history, can_diagnostic_counters, can_diagnostics, can_fault_status, can_operation, can_operation_tech, can_operation_tech_delta, can_pack_summary, can_thresholds, can_update_timing, CanController, Command, Thresholds, THRESHOLD_PACK_FLOOR};
+use ltc_management::LTC6811;
+use board::{Ltc, LtcSpi};
 use usb_serial::prepare_config;
+use config::{
+    BAL_START_HYSTERESIS, BAL_START_VOLTAGE, CAL_ATTEMPTS, CAL_MAX_DEVIATION, CAL_MAX_SPREAD, CAL_SAMPLES, CAN_BITRATE,
+    CHARGE_CURRENT_LIMIT, CHARGE_CURRENT_WARN, CURRENT_FROZEN_CELL_DELTA, CURRENT_FROZEN_WINDOWS, CURRENT_TRIP_DEBOUNCE_MS,
+    CURRENT_WARN_DEBOUNCE_MS, DISCHARGE_CURRENT_LIMIT, DISCHARGE_CURRENT_WARN, ERR_CHECK_FAULT_HIGH, FAULT_DEBOUNCE_MS, FAULT_RESPONSES,
+    FAULT_LED_BLINK_MS, IDLE_TIMEOUT_MS, IDLE_VOLT_DELTA, LOG_PERIOD_MS, LOW_POWER_LTC_PERIOD_MS, LOW_POWER_TX_FACTOR,
+    LTC_MAX_FAILURES, MAX_TEMP_RATE, MEASUREMENT_STALE_MS, MIN_PACK_VOLTAGE, PACK_MISMATCH_LIMIT, POWER_CHECK_MIN_CURRENT,
+    POWER_CHECK_WINDOW_MS, POWER_CHECK_WRONG_WAY, REST_CURRENT,
+    STALE_CELLS_MAX_MS, THERMISTOR_DIE_DEBOUNCE_MS, THERMISTOR_DIE_MAX_DELTA, TX_PERIODS_MS, TX_TICK_MS, VOLTAGE_OFFSET,
+    VOLT_TRIP_SAMPLES, VOLT_TRIP_WINDOW,
+};
 
 static BMS: StaticCell<Mutex<CriticalSectionRawMutex, SLAVEBMS>> = StaticCell::new();
 static ERR_CHECK: StaticCell<Mutex<CriticalSectionRawMutex, Output>> = StaticCell::new();
 static CAN: StaticCell<Mutex<CriticalSectionRawMutex, CanController>> = StaticCell::new();
-static SPI: StaticCell<Mutex<CriticalSectionRawMutex, SpiDevice>> = StaticCell::new();
-static LTC: StaticCell<Mutex<CriticalSectionRawMutex, LTC6811>> = StaticCell::new();
+static SPI: StaticCell<Mutex<CriticalSectionRawMutex, LtcSpi>> = StaticCell::new();
+static LTC: StaticCell<Mutex<CriticalSectionRawMutex, Ltc>> = StaticCell::new();
 static IS_BALANCE: StaticCell<Mutex<CriticalSectionRawMutex, bool>> = StaticCell::new();
 static IS_TECH: StaticCell<Mutex<CriticalSectionRawMutex, bool>> = StaticCell::new();
+static IS_SHUTDOWN: StaticCell<Mutex<CriticalSectionRawMutex, bool>> = StaticCell::new();
+
+// Raised by ltc_function once the BMS history holds real readings, send_can waits on it
+// so no zeroed cells go out on the bus at startup
+static LTC_READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// Pack under-voltage floor in use, 100uV like tot_volt, set by SetPackMinVoltage.
+// PACK_MIN_SET tells a commanded floor from the default, for GetThresholds
+static PACK_MIN_VOLTAGE: AtomicU32 = AtomicU32::new(MIN_PACK_VOLTAGE);
+static PACK_MIN_SET: AtomicBool = AtomicBool::new(false);
+// Set by current_sense while the current ADC channel looks frozen
+static CURRENT_FROZEN: AtomicBool = AtomicBool::new(false);
+// Tech set sent as one delta encoded TechDelta frame instead of Tech1..Tech3
+static TECH_DELTA: AtomicBool = AtomicBool::new(false);
+// Fired by ltc_function after every successful update(), UPDATE_SEQ counts them so
+// consumers can tell a fresh sample from one they already used
+static BMS_UPDATED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static UPDATE_SEQ: AtomicU32 = AtomicU32::new(0);
+// Uptime (ms) of the last successful update(), see MEASUREMENT_STALE_MS
+static LAST_UPDATE_MS: AtomicU32 = AtomicU32::new(0);
+// Set by the ResetLtc command, ltc_function clears its transient faults on it
+static LTC_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// Fault and warning bitfields published by ltc_function, sent by send_can in the ErrorId frame
+static FAULTS: AtomicU16 = AtomicU16::new(0);
+static WARNINGS: AtomicU8 = AtomicU8::new(0);
+// Low power mode, entered by ltc_function on a parked car, left on CAN traffic or current.
+// LOW_POWER_WAKE cuts short the slow LTC period, LAST_CAN_RX_MS is the uptime of the last frame
+static LOW_POWER: AtomicBool = AtomicBool::new(false);
+static LOW_POWER_WAKE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static LAST_CAN_RX_MS: AtomicU32 = AtomicU32::new(0);
+// Per-cell and per-thermistor lines in the periodic log, toggled over USB, off by default
+static VERBOSE_LOG: AtomicBool = AtomicBool::new(false);
+// Highest StartupMilestone main got to, see startup_milestone
+static STARTUP_MILESTONE: AtomicU8 = AtomicU8::new(StartupMilestone::Reset as u8);
+// Uptime (ms) until which ForceBalance keeps balancing on, 0 = not forced
+static FORCE_BALANCE_UNTIL_MS: AtomicU32 = AtomicU32::new(0);
+// Fault bits forced by InjectFault and the uptime (ms) they stay forced until
+#[cfg(feature = "fault-injection")]
+static INJECTED_FAULTS: AtomicU16 = AtomicU16::new(0);
+#[cfg(feature = "fault-injection")]
+static INJECTED_UNTIL_MS: AtomicU32 = AtomicU32::new(0);
 
 // static TEMP_HC: StaticCell<Mutex<CriticalSectionRawMutex, [u16; 2]>> = StaticCell::new();
 
 
-const VOLTAGE_OFFSET: f32 = 1650f32; //mV
+// FAULT_RESPONSES folded into the masks ltc_function applies every cycle.
+// A table missing a fault bit, or listing one twice, doesn't build
+const OPEN_LOOP_FAULTS: u16 = {
+    assert!(covers_all(FAULT_RESPONSES), "FAULT_RESPONSES needs exactly one entry per fault bit");
+    open_loop_mask(FAULT_RESPONSES)
+};
+const LATCHED_FAULTS: u16 = latch_mask(FAULT_RESPONSES);
+const VOLTAGE_LED_FAULTS: u16 = led_mask(FAULT_RESPONSES, FaultLed::Voltage);
+const TEMP_LED_FAULTS: u16 = led_mask(FAULT_RESPONSES, FaultLed::Temperature);
+
+// Warning bits, ErrorId byte 3: reported only, err_check and balancing ignore them
+const WARN_CURRENT: u8 = 1 << 0;
+const WARN_THERMISTOR: u8 = 1 << 1;
+const WARN_CURRENT_SIGN: u8 = 1 << 2;
+
+// Steps of main in the order they happen. A board hanging at boot stops logging
+// after the last one it reached, the USB command `boot` reports it afterwards
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+enum StartupMilestone {
+    Reset,
+    ClocksUp,
+    CanUp,
+    UsbUp,
+    CurrentSenseSpawned,
+    SpiUp,
+    LtcInitDone,
+    Ready,
+}
+
+impl StartupMilestone {
+    const ALL: [StartupMilestone; 8] = [
+        StartupMilestone::Reset,
+        StartupMilestone::ClocksUp,
+        StartupMilestone::CanUp,
+        StartupMilestone::UsbUp,
+        StartupMilestone::CurrentSenseSpawned,
+        StartupMilestone::SpiUp,
+        StartupMilestone::LtcInitDone,
+        StartupMilestone::Ready,
+    ];
+}
+
+fn reach_milestone(milestone: StartupMilestone) {
+    STARTUP_MILESTONE.fetch_max(milestone as u8, Ordering::Relaxed);
+    info!("Startup: {}", milestone);
+}
+
+// Highest milestone reached since reset
+fn startup_milestone() -> StartupMilestone {
+    let raw = STARTUP_MILESTONE.load(Ordering::Relaxed) as usize;
+    StartupMilestone::ALL[raw.min(StartupMilestone::ALL.len() - 1)]
+}
 
+// One byte version of the fault field for the PackSummary frame, a bit per kind of fault:
+// bit0 voltage, bit1 temperature, bit2 current, bit3 measurement, bit4 communication, bit7 any
+fn fault_summary(faults: u16) -> u8 {
+    ((faults & (FAULT_VOLT | FAULT_PACK_UV) != 0) as u8)
+        | (((faults & (FAULT_TEMP | FAULT_GRADIENT) != 0) as u8) << 1)
+        | (((faults & (FAULT_CURRENT | FAULT_CURRENT_ADC) != 0) as u8) << 2)
+        | (((faults & (FAULT_FLAGS | FAULT_SUM | FAULT_READ | FAULT_STALE | FAULT_PACK) != 0) as u8) << 3)
+        | (((faults & (FAULT_COMM | FAULT_SPI_CS) != 0) as u8) << 4)
+        | (((faults != 0) as u8) << 7)
+}
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) -> ! {
     let p = embassy_stm32::init(prepare_config());
+    backup::init();
+    let saved = backup::load();
+    reach_milestone(StartupMilestone::ClocksUp);
 
     let current_adc: embassy_stm32::adc::Adc<'static, ADC1, > = Adc::new(p.ADC1);
     let current_pin: embassy_stm32::peripherals::PA1 = p.PA1;
+    let pack_adc: embassy_stm32::adc::Adc<'static, ADC2> = Adc::new(p.ADC2);
+    let pack_pin: embassy_stm32::peripherals::PA3 = p.PA3;
 
-    let (can, rx1, tx1) = CanController::new_can2(p.CAN2, p.PB12, p.PB13, 500_000, p.CAN1, p.PA11, p.PA12).await;
+    let (can, rx1, tx1) = CanController::new_can2(p.CAN2, p.PB12, p.PB13, CAN_BITRATE, p.CAN1, p.PA11, p.PA12).await;
     let can_mutex = Mutex::new(can);
     let can = StaticCell::init(&CAN, can_mutex);
+    reach_milestone(StartupMilestone::CanUp);
     
     Serial::init(p.USB_OTG_FS, tx1, rx1, & spawner);
+    reach_milestone(StartupMilestone::UsbUp);
+
+    if let Some(record) = &saved {
+        log_saved_shutdown(record);
+    }
+
+    #[cfg(feature = "fault-injection")]
+    defmt::warn!("Fault injection build, not for the car");
+
+    #[cfg(feature = "can-self-test")]
+    {
+        if can.lock().await.self_test().await {
+            info!("CAN self-test passed");
+        } else {
+            defmt::error!("CAN self-test failed, check controller and transceiver");
+        }
+    }
 
     let debug_led = Output::new(p.PC13, Level::Low, Speed::High);
     let temp_led = Output::new(p.PC9, Level::Low, Speed::High);
     let voltage_led = Output::new(p.PC11, Level::Low, Speed::High);
 
-    let err_check = Output::new(p.PA2, Level::Low, Speed::High);
+    // Safe from the first instruction: fault level until ltc_function has a valid reading
+    let err_check = Output::new(p.PA2, Level::from(ERR_CHECK_FAULT_HIGH), Speed::High);
     let err_check_mutex = Mutex::new(err_check);
     let err_check = StaticCell::init(&ERR_CHECK, err_check_mutex);
 
@@ -70,42 +223,216 @@ async fn main(spawner: Spawner) -> ! {
     let is_tech_mutex = Mutex::new(is_tech);
     let is_tech = StaticCell::init(&IS_TECH, is_tech_mutex);
 
-    let bms = setup_bms();
+    let is_shutdown = false;
+    let is_shutdown_mutex = Mutex::new(is_shutdown);
+    let is_shutdown = StaticCell::init(&IS_SHUTDOWN, is_shutdown_mutex);
+
+    let bms = setup_bms(saved.as_ref());
     let bms_mutex = Mutex::new(bms);
     let bms = StaticCell::init(&BMS, bms_mutex);
 
     spawner.spawn(current_sense(current_adc, current_pin, bms)).unwrap();
+    spawner.spawn(pack_voltage_sense(pack_adc, pack_pin, bms)).unwrap();
+    reach_milestone(StartupMilestone::CurrentSenseSpawned);
     
-    spawner.spawn(send_can(bms, can, is_tech)).unwrap();
-
     //info!("Hello world over USB-CDC!");
 
-    let spi: SpiDevice<'static> = SpiDevice::new(p.SPI1, p.PA5, p.PA7, p.PA6, p.PA4, p.DMA2_CH3, p.DMA2_CH0).await;
+    let spi = board::ltc_spi(p.SPI1, p.PA5, p.PA7, p.PA6, p.PA4, p.DMA2_CH3, p.DMA2_CH0);
     let spi_mutex = Mutex::new(spi);
     let spi = StaticCell::init(&SPI, spi_mutex);
+    reach_milestone(StartupMilestone::SpiUp);
 
     let mut ltc = LTC6811::new(spi, bms).await;  // Initialize LTC6811
     match ltc.init().await {
-        Ok(_) => {},//info!("LTC6811 initialized successfully"),
+        Ok(_) => info!("LTC6811 initialized"),
         Err(_) => defmt::error!("Failed to initialize LTC6811"),
     }
+    // Reached on failure too, the failed updates in ltc_function end in FAULT_COMM
+    reach_milestone(StartupMilestone::LtcInitDone);
 
     let ltc_mutex = Mutex::new(ltc);
     let ltc = StaticCell::init(&LTC, ltc_mutex);
-    spawner.spawn(ltc_function(bms, ltc, err_check, can, debug_led, voltage_led, temp_led, is_balance)).unwrap();
 
-    spawner.spawn(read_can(is_balance, can, is_tech)).unwrap();
+    spawner.spawn(send_can(bms, can, is_tech, ltc, is_balance)).unwrap();
+    spawner.spawn(ltc_function(bms, ltc, err_check, debug_led, voltage_led, temp_led, is_balance, is_shutdown)).unwrap();
+
+    spawner.spawn(read_can(is_balance, can, is_tech, ltc, bms, is_shutdown)).unwrap();
+    spawner.spawn(usb_commands(ltc, bms)).unwrap();
+    reach_milestone(StartupMilestone::Ready);
 
     loop {
         embassy_time::Timer::after_millis(10000).await;
     }
 }
 
-fn setup_bms() -> SLAVEBMS{
-    let bms = SLAVEBMS::new();
+// The saved topology is applied before any reading, so the unwired channels never fault
+fn setup_bms(saved: Option<&backup::Persisted>) -> SLAVEBMS{
+    let mut bms = SLAVEBMS::new();
+    if let Some(topology) = saved.and_then(backup::Persisted::topology) {
+        bms.set_topology(topology);
+        info!("Topology {} restored from backup SRAM", topology);
+    }
     bms
 }
 
+// Drive err_check to the fault or the healthy level, polarity set by ERR_CHECK_FAULT_HIGH
+fn set_err_check(pin: &mut Output<'static>, fault: bool) {
+    pin.set_level(Level::from(fault == ERR_CHECK_FAULT_HIGH));
+}
+
+// Put err_check at the fault level with plain register writes, for the panic and HardFault paths:
+// the Output lives behind a Mutex owned by ltc_function, which may be the code that just failed.
+// Safe to call at any point, also before embassy_stm32::init: the GPIOA clock and the output mode
+// of PA2 are set again. Balancing FETs are not touched: DCTO is 0, so the LTC6811 watchdog clears
+// the discharge bits on its own ~2s after the SPI traffic stops
+pub fn force_safe_state() {
+    use embassy_stm32::pac;
+    use embassy_stm32::pac::gpio::vals::Moder;
+    const ERR_CHECK_PIN: usize = 2; // PA2, see err_check in main
+
+    pac::RCC.ahb1enr().modify(|w| w.set_gpioaen(true));
+    if ERR_CHECK_FAULT_HIGH {
+        pac::GPIOA.bsrr().write(|w| w.set_bs(ERR_CHECK_PIN, true));
+    } else {
+        pac::GPIOA.bsrr().write(|w| w.set_br(ERR_CHECK_PIN, true));
+    }
+    pac::GPIOA.moder().modify(|w| w.set_moder(ERR_CHECK_PIN, Moder::OUTPUT));
+}
+
+// panic-probe (rtt-log) ends a panic in a HardFault, so does our USB panic handler: either way
+// the safety output is forced before the core halts
+#[cortex_m_rt::exception]
+unsafe fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    force_safe_state();
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+// Fault bits currently forced by InjectFault, 0 once the injection expired
+#[cfg(feature = "fault-injection")]
+fn injected_faults() -> u16 {
+    let now = embassy_time::Instant::now().as_millis() as u32;
+    if now < INJECTED_UNTIL_MS.load(Ordering::Relaxed) {
+        INJECTED_FAULTS.load(Ordering::Relaxed)
+    } else {
+        0
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn injected_faults() -> u16 {
+    0
+}
+
+// No update() within MEASUREMENT_STALE_MS, stretched by the slow LTC period in low power.
+// Also true from boot until the first update
+fn measurements_stale(now_ms: u64) -> bool {
+    let mut limit = MEASUREMENT_STALE_MS;
+    if LOW_POWER.load(Ordering::Relaxed) {
+        limit += LOW_POWER_LTC_PERIOD_MS;
+    }
+    let last = LAST_UPDATE_MS.load(Ordering::Relaxed) as u64;
+    UPDATE_SEQ.load(Ordering::Relaxed) == 0 || now_ms.saturating_sub(last) > limit
+}
+
+// ForceBalance still running
+fn balance_forced() -> bool {
+    let now = embassy_time::Instant::now().as_millis() as u32;
+    now < FORCE_BALANCE_UNTIL_MS.load(Ordering::Relaxed)
+}
+
+// Leave low power, called from whichever task saw the activity
+fn exit_low_power(reason: &str) {
+    if LOW_POWER.swap(false, Ordering::Relaxed) {
+        info!("Leaving low power: {}", reason);
+        LOW_POWER_WAKE.signal(());
+    }
+}
+
+// Summary only unless VERBOSE_LOG is set, every cell and thermistor is a lot of USB traffic each period
+fn log_snapshot(snapshot: &BmsSnapshot) {
+    // Cells are read in 0.1mV and temperatures in 0.1°C, logs use mV and °C
+    let min_mv = snapshot.min_volt.millivolts();
+    let max_mv = snapshot.max_volt.millivolts();
+    if VERBOSE_LOG.load(Ordering::Relaxed) {
+        info!(
+            "Pack {} mV, cells mV {} min {} max {} avg {} delta {}",
+            snapshot.pack_voltage_mv(), snapshot.cell_volts.map(DeciMilliVolt::millivolts), min_mv, max_mv, snapshot.avg_volt.millivolts(), max_mv.saturating_sub(min_mv)
+        );
+        info!("Temps C {}", snapshot.temps.map(DeciCelsius::celsius));
+    } else {
+        info!(
+            "Pack {} mV, cells mV min {} max {} avg {} delta {}, temps C min {} max {}",
+            snapshot.pack_voltage_mv(), min_mv, max_mv, snapshot.avg_volt.millivolts(), max_mv.saturating_sub(min_mv),
+            snapshot.min_temp.celsius(), snapshot.max_temp.celsius()
+        );
+    }
+    info!("Current {} avg {} (100uA)", snapshot.current.into_raw(), snapshot.current_avg.into_raw());
+}
+
+// One line per counter, in Counter order like the DiagnosticCounters frames
+fn log_diagnostics(counts: &[u32; Counter::COUNT]) {
+    for (counter, count) in Counter::ALL.iter().zip(counts) {
+        info!("{}: {}", counter, count);
+    }
+}
+
+// Everything a log capture needs to know which tunables the board was running with.
+// Raw units as in config.rs: cells 0.1mV, temperatures 0.1°C, current 100uA, pack 100uV
+#[cfg(feature = "config-log")]
+fn log_config() {
+    use config::{
+        BAL_EPSILON, BAL_EPSILON_WARM, BAL_WARM_TEMP, HISTORY_PERIOD_MS, NUM_CELLS, NUM_HISTORY, NUM_TERMISTORS,
+        TEMP_OVERSAMPLES, THERMISTOR_MUX_STEPS, THERMISTOR_TABLE,
+    };
+    info!(
+        "Config: {} cells, {} thermistors ({}, {} mux steps), CAN {} bit/s, LTC ADC {} Hz, {} temp oversamples",
+        NUM_CELLS, NUM_TERMISTORS, if THERMISTOR_TABLE.is_some() {"table"} else {"Beta"}, THERMISTOR_MUX_STEPS, CAN_BITRATE,
+        ltc_management::ltc6811::ADC_MODE_HZ, TEMP_OVERSAMPLES
+    );
+    info!("Config: history {} samples every {} ms", NUM_HISTORY, HISTORY_PERIOD_MS);
+    info!(
+        "Config: cell {}..{} ({} of {} readings), temp {}..{}, pack floor {}, current {}..{}, debounce {} ms, err_check fault {}",
+        VOLTAGES::MINVOLTAGE.as_raw(), VOLTAGES::MAXVOLTAGE.as_raw(), VOLT_TRIP_SAMPLES, VOLT_TRIP_WINDOW,
+        TEMPERATURES::MINTEMP._as_raw(), TEMPERATURES::MAXTEMP._as_raw(),
+        PACK_MIN_VOLTAGE.load(Ordering::Relaxed), CHARGE_CURRENT_LIMIT.into_raw(), DISCHARGE_CURRENT_LIMIT.into_raw(),
+        FAULT_DEBOUNCE_MS, if ERR_CHECK_FAULT_HIGH {"high"} else {"low"}
+    );
+    info!(
+        "Config: balancing epsilon {} ({} above {}), start {} hysteresis {}, rest below {}",
+        BAL_EPSILON, BAL_EPSILON_WARM, BAL_WARM_TEMP.into_raw(), BAL_START_VOLTAGE.into_raw(),
+        BAL_START_HYSTERESIS.into_raw(), REST_CURRENT.into_raw()
+    );
+}
+
+// Auto-zero of the current sensor, in mV. A window whose samples spread too much, or whose
+// average is far from the nominal offset, saw current flowing and is retried
+async fn calibrate_current_offset(
+    adc: &mut embassy_stm32::adc::Adc<'static, ADC1>,
+    curr_pin: &mut embassy_stm32::peripherals::PA1
+) -> Option<f32> {
+    for attempt in 0..CAL_ATTEMPTS {
+        let mut count: u64 = 0;
+        let mut min = u16::MAX;
+        let mut max = 0u16;
+        for _ in 0..CAL_SAMPLES {
+            let sample = adc.blocking_read(curr_pin);
+            count = count.wrapping_add(sample as u64);
+            min = min.min(sample);
+            max = max.max(sample);
+            embassy_time::Timer::after_millis(1).await;
+        }
+
+        let offset = adc_to_mv(count, CAL_SAMPLES);
+        if max - min <= CAL_MAX_SPREAD && (offset - VOLTAGE_OFFSET).abs() <= CAL_MAX_DEVIATION {
+            return Some(offset);
+        }
+        defmt::warn!("Current auto-zero attempt {} rejected, spread {} offset {} mV", attempt, max - min, offset);
+    }
+    None
+}
+
 #[embassy_executor::task]
 async fn current_sense(
     mut adc: embassy_stm32::adc::Adc<'static, ADC1>,
@@ -115,114 +442,607 @@ async fn current_sense(
     adc.set_resolution(Resolution::BITS12);
     embassy_time::Timer::after_millis(100).await;
 
-    let mut count: u64 = 0;
-    for _ in 0..10 {
-        count = count.wrapping_add(adc.blocking_read(&mut curr_pin) as u64);
-        embassy_time::Timer::after_millis(1).await;
-    }
-
-    let no_current_offset = ((count as f32)/10.0f32) * 3300f32 / (4095 as f32);
+    let no_current_offset = match calibrate_current_offset(&mut adc, &mut curr_pin).await {
+        Some(offset) => offset,
+        None => {
+            defmt::error!("Current auto-zero never settled, using default offset {} mV", VOLTAGE_OFFSET);
+            VOLTAGE_OFFSET
+        }
+    };
     let factor = no_current_offset / VOLTAGE_OFFSET;
 
+    let mut count: u64;
+    let mut frozen_windows: u32 = 0;
+    let mut frozen_avg_volt = DeciMilliVolt::default();
     loop {
         count = 0;
+        let mut lowest = u16::MAX;
+        let mut highest = 0u16;
         for _ in 0..50 {
-            count = count.wrapping_add(adc.blocking_read(&mut curr_pin) as u64);
+            let sample = adc.blocking_read(&mut curr_pin);
+            lowest = lowest.min(sample);
+            highest = highest.max(sample);
+            count = count.wrapping_add(sample as u64);
             embassy_time::Timer::after_micros(200).await;
         }
 
-        let mut f_curr = ((count as f32)/50.0f32) * 3300f32 / (4095 as f32);
-        f_curr = ((f_curr - no_current_offset)/(9.2f32*factor))*10000f32;
-
-        let rounded: i32 = if f_curr >= 0.0f32 {
-            roundf(f_curr).max(0.0) as i32
+        if lowest == highest {
+            if frozen_windows == 0 {
+                frozen_avg_volt = bms.lock().await.avg_volt();
+            }
+            frozen_windows = frozen_windows.saturating_add(1);
+            if frozen_windows >= CURRENT_FROZEN_WINDOWS && !CURRENT_FROZEN.load(Ordering::Relaxed) {
+                let avg_volt = bms.lock().await.avg_volt();
+                // Cells moving while the current reads perfectly flat: the channel is stuck
+                if avg_volt.abs_diff(frozen_avg_volt) > CURRENT_FROZEN_CELL_DELTA {
+                    defmt::error!("Current ADC frozen at {} for {} windows while cells moved", lowest, frozen_windows);
+                    CURRENT_FROZEN.store(true, Ordering::Relaxed);
+                    DIAGNOSTICS.bump(Counter::StuckChannel);
+                }
+            }
         } else {
-            roundf(f_curr).min(0.0) as i32
-        };
+            frozen_windows = 0;
+            CURRENT_FROZEN.store(false, Ordering::Relaxed);
+        }
 
-        let mut bms_data = bms.lock().await;
+        let current = current_from_sensor_mv(adc_to_mv(count, 50), no_current_offset, factor);
 
-        bms_data.update_current(rounded);
+        let mut bms_data = bms.lock().await;
+        bms_data.update_current(current);
 
         drop(bms_data);
+        if current.abs() > REST_CURRENT {
+            exit_low_power("current");
+        }
         embassy_time::Timer::after_millis(10).await;
     }
 }
 
+#[embassy_executor::task]
+async fn pack_voltage_sense(
+    mut adc: embassy_stm32::adc::Adc<'static, ADC2>,
+    mut pack_pin: embassy_stm32::peripherals::PA3,
+    bms: &'static Mutex<CriticalSectionRawMutex, SLAVEBMS>
+) {
+    adc.set_resolution(Resolution::BITS12);
+    embassy_time::Timer::after_millis(100).await;
+
+    let mut count: u64;
+    loop {
+        count = 0;
+        for _ in 0..50 {
+            count = count.wrapping_add(adc.blocking_read(&mut pack_pin) as u64);
+            embassy_time::Timer::after_micros(200).await;
+        }
+
+        // mV at the pin, times the divider, to the 100uV steps of tot_volt
+        let pack_volt = pack_from_pin_mv(adc_to_mv(count, 50));
+
+        let mut bms_data = bms.lock().await;
+        bms_data.update_pack_volt(pack_volt);
+        drop(bms_data);
+        embassy_time::Timer::after_millis(50).await;
+    }
+}
 
 #[embassy_executor::task]
 async fn send_can(
     bms: &'static Mutex<CriticalSectionRawMutex, SLAVEBMS>, 
     can: &'static Mutex<CriticalSectionRawMutex, CanController<'static>>,
-    is_tech: &'static Mutex<CriticalSectionRawMutex, bool>
+    is_tech: &'static Mutex<CriticalSectionRawMutex, bool>,
+    ltc: &'static Mutex<CriticalSectionRawMutex, Ltc>,
+    is_balance: &'static Mutex<CriticalSectionRawMutex, bool>
 ){
+    // Until the readings are valid only the fault status goes out
+    let mut ready = false;
+    let mut next_due = [0u64; TX_PERIODS_MS.len()];
+    // Measurement frames wait for a sample newer than the one they last carried,
+    // so the same data never goes out twice. Status frames only follow their period
+    let mut sent_seq = [u32::MAX; TX_PERIODS_MS.len()];
+    let mut was_stale = false;
     loop {
-        let bms_data = bms.lock().await;
-        let mut can_data = can.lock().await;
-        match can_operation(&bms_data, &mut can_data).await {
-            Ok(_) => {},
-            Err(_) => {}
+        ready = ready || LTC_READY.try_take().is_some();
+        let now = embassy_time::Instant::now().as_millis();
+        let seq = UPDATE_SEQ.load(Ordering::Relaxed);
+        for (i, (msg, period)) in TX_PERIODS_MS.iter().enumerate() {
+            if now < next_due[i] || (!ready && *msg != CanMsg::ErrorId) {
+                continue;
+            }
+            let measurement = !matches!(msg, CanMsg::ErrorId | CanMsg::BalanceStatus | CanMsg::UpdateTiming);
+            if measurement && sent_seq[i] == seq {
+                continue;
+            }
+            sent_seq[i] = seq;
+            // Faults keep their rate in low power, everything else slows down
+            let slow = LOW_POWER.load(Ordering::Relaxed) && *msg != CanMsg::ErrorId;
+            next_due[i] = now + if slow {period * LOW_POWER_TX_FACTOR} else {*period};
+
+            match msg {
+                CanMsg::Tech1 => {
+                    let tech: bool = *is_tech.lock().await;
+                    if tech {
+                        let snapshot = bms.lock().await.snapshot();
+                        let mut can_data = can.lock().await;
+                        if TECH_DELTA.load(Ordering::Relaxed) {
+                            let _ = can_operation_tech_delta(&snapshot, &mut can_data).await;
+                        } else {
+                            let _ = can_operation_tech(&snapshot, &mut can_data).await;
+                        }
+                    }
+                }
+
+                CanMsg::BalanceStatus => {
+                    let balance: bool = *is_balance.lock().await;
+                    let ltc_data = ltc.lock().await;
+                    let dry_run = ltc_data.balance_dry_run();
+                    let discharge_bitmap = ltc_data.discharge_bitmap();
+                    let planned_bitmap = ltc_data.planned_bitmap();
+                    let state = ltc_data.balancing_state();
+                    drop(ltc_data);
+                    let mut can_data = can.lock().await;
+                    let _ = can_balance_status(balance, dry_run, discharge_bitmap, planned_bitmap, state, &mut can_data).await;
+                }
+
+                CanMsg::PackSummary => {
+                    let snapshot = bms.lock().await.snapshot();
+                    let summary = fault_summary(FAULTS.load(Ordering::Relaxed));
+                    let mut can_data = can.lock().await;
+                    let _ = can_pack_summary(&snapshot, summary, &mut can_data).await;
+                }
+
+                CanMsg::ErrorId => {
+                    let faults = FAULTS.load(Ordering::Relaxed);
+                    let warnings = WARNINGS.load(Ordering::Relaxed);
+                    let severity = max_severity(FAULT_RESPONSES, faults);
+                    let stale = measurements_stale(now);
+                    if stale != was_stale {
+                        if stale {
+                            defmt::warn!("No LTC update since {} ms, measurements stale", LAST_UPDATE_MS.load(Ordering::Relaxed));
+                        } else {
+                            info!("LTC updates back, measurements fresh");
+                        }
+                        was_stale = stale;
+                    }
+                    let mut can_data = can.lock().await;
+                    let _ = can_fault_status(faults, warnings, severity, stale, &mut can_data).await;
+                }
+
+                CanMsg::UpdateTiming => {
+                    let timing = ltc.lock().await.update_timing();
+                    let mut can_data = can.lock().await;
+                    let _ = can_update_timing(&timing, &mut can_data).await;
+                }
+
+                _ => {
+                    let snapshot = bms.lock().await.snapshot();
+                    let mut can_data = can.lock().await;
+                    let _ = can_operation(&snapshot, &mut can_data, *msg).await;
+                }
+            }
         }
-        drop(can_data);
-        drop(bms_data);
-        embassy_time::Timer::after_millis(10).await;
 
-        let is_tech_data = is_tech.lock().await;
-        let tech: bool = *is_tech_data;
-        drop(is_tech_data);
-        embassy_time::Timer::after_millis(1).await;
-        if tech == true{
-            let bms_data = bms.lock().await;
-            let mut can_data = can.lock().await;
-            match can_operation_tech(&bms_data, &mut can_data).await {
-                Ok(_) => {},
-                Err(_) => {}
+        // Wake up early on a fresh sample, frames held back for it go out right away
+        select(BMS_UPDATED.wait(), embassy_time::Timer::after_millis(TX_TICK_MS)).await;
+    }
+}
+
+// Line based diagnostics typed into the USB serial port, answers go out as defmt logs
+#[embassy_executor::task]
+async fn usb_commands(
+    ltc: &'static Mutex<CriticalSectionRawMutex, Ltc>,
+    bms: &'static Mutex<CriticalSectionRawMutex, SLAVEBMS>
+) {
+    loop {
+        let line = Serial::read_line::<32>().await;
+        match line.trim() {
+            "cfg" => {
+                let mut ltc_data = ltc.lock().await;
+                let _ = ltc_data.dump_config().await;
+                drop(ltc_data);
+            }
+            "baltime" => {
+                let ltc_data = ltc.lock().await;
+                let times = ltc_data.balance_time_ms();
+                drop(ltc_data);
+                info!("Balancing time per cell, s: {}", times.map(|ms| ms / 1000));
+            }
+            "baltime reset" => {
+                let mut ltc_data = ltc.lock().await;
+                ltc_data.reset_balance_time();
+                drop(ltc_data);
+                info!("Balancing time reset");
+            }
+            "raw on" | "raw off" => {
+                let raw = line.trim() == "raw on";
+                let mut bms_data = bms.lock().await;
+                bms_data.set_raw(raw);
+                drop(bms_data);
+                defmt::warn!("Raw readings {}", if raw {"on, no history average"} else {"off"});
+            }
+            "raw" => {
+                let bms_data = bms.lock().await;
+                let raw = bms_data.raw();
+                drop(bms_data);
+                info!("Raw readings {}", if raw {"on"} else {"off"});
+            }
+            "verbose on" | "verbose off" => {
+                let verbose = line.trim() == "verbose on";
+                VERBOSE_LOG.store(verbose, Ordering::Relaxed);
+                info!("Per-cell log {}", if verbose {"on"} else {"off"});
+            }
+            "boot" => info!("Startup reached {}", startup_milestone()),
+            "diag" => log_diagnostics(&DIAGNOSTICS.snapshot()),
+            "diag clear" => {
+                log_diagnostics(&DIAGNOSTICS.clear());
+                info!("Diagnostic counters cleared");
+            }
+            #[cfg(feature = "spi-passthrough")]
+            cmd if cmd.starts_with("spi ") => spi_passthrough(ltc, &cmd[4..]).await,
+            "" => {}
+            other => defmt::warn!("Unknown USB command: {}", other),
+        }
+    }
+}
+
+// `spi 0002`: the command as 4 hex digits, CMD0 first, see raw_cmd_read. Refused while the
+// balancing loop drives the LTC, a stray command could change what it relies on
+#[cfg(feature = "spi-passthrough")]
+async fn spi_passthrough(ltc: &'static Mutex<CriticalSectionRawMutex, Ltc>, arg: &str) {
+    let Ok(cmd) = u16::from_str_radix(arg.trim(), 16) else {
+        defmt::warn!("spi: expected a 4 digit hex command");
+        return;
+    };
+    let mut ltc_data = ltc.lock().await;
+    if balance_forced() || matches!(ltc_data.balancing_state(), BalancingState::Active | BalancingState::Waiting) {
+        defmt::warn!("spi: refused, balancing is running");
+        return;
+    }
+    let result = ltc_data.raw_cmd_read(cmd.to_be_bytes()).await;
+    drop(ltc_data);
+    match result {
+        Ok((data, pec_rx, pec_calc)) => info!(
+            "spi {:#06x}: data {:02x} PEC rx {:02x} calc {:02x} {}",
+            cmd, data, pec_rx, pec_calc, if pec_rx == pec_calc {"OK"} else {"MISMATCH"}
+        ),
+        Err(_) => defmt::error!("spi {:#06x}: transfer failed", cmd),
+    }
+}
+
+// Shared state the CAN command handlers work on, built once by read_can
+#[derive(Clone, Copy)]
+struct RxContext {
+    is_balance: &'static Mutex<CriticalSectionRawMutex, bool>,
+    can: &'static Mutex<CriticalSectionRawMutex, CanController<'static>>,
+    is_tech: &'static Mutex<CriticalSectionRawMutex, bool>,
+    ltc: &'static Mutex<CriticalSectionRawMutex, Ltc>,
+    bms: &'static Mutex<CriticalSectionRawMutex, SLAVEBMS>,
+    is_shutdown: &'static Mutex<CriticalSectionRawMutex, bool>,
+}
+
+// One handler per entry of RX_COMMANDS. A new command needs a CanMsg, an RX_COMMANDS entry and an arm here
+async fn dispatch_rx(ctx: RxContext, msg: CanMsg, bytes: &[u8]) {
+    match msg {
+        CanMsg::Balancing => rx_balancing(ctx, bytes).await,
+        CanMsg::Tech => rx_tech(ctx, bytes).await,
+        CanMsg::RunSelfTest => rx_run_self_test(ctx).await,
+        CanMsg::ForceBalance => rx_force_balance(ctx, bytes).await,
+        CanMsg::ManualDischarge => rx_manual_discharge(ctx, bytes).await,
+        CanMsg::RequestCellHistory => rx_request_cell_history(ctx, bytes).await,
+        CanMsg::SetPackMinVoltage => rx_set_pack_min_voltage(bytes),
+        CanMsg::GetThresholds => rx_get_thresholds(ctx).await,
+        CanMsg::SetTopology => rx_set_topology(ctx, bytes).await,
+        CanMsg::GetDiagnostics => rx_get_diagnostics(ctx, bytes).await,
+        CanMsg::ResetLtc => rx_reset_ltc(ctx).await,
+        #[cfg(feature = "fault-injection")]
+        CanMsg::InjectFault => rx_inject_fault(bytes),
+        CanMsg::PrepareShutdown => rx_prepare_shutdown(ctx).await,
+        _ => rx_unhandled(msg.as_raw()),
+    }
+}
+
+// Modes of the Balancing and Tech commands, byte 1
+const BALANCING_MODE_NORMAL: u8 = 0;
+const BALANCING_MODE_DRY_RUN: u8 = 1;
+const TECH_MODE_CELLS: u8 = 0;
+const TECH_MODE_DELTA: u8 = 1;
+
+// Anything on the bus we don't act on: other nodes' traffic, or a command missing its handler
+fn rx_unhandled(id: u16) {
+    defmt::debug!("CAN RX {:#x} not handled", id);
+}
+
+// Command layout (see Command). Mode 0: balance, 1: dry run, the bitmap is computed and reported
+// but never written. Parameter reserved, 0
+async fn rx_balancing(ctx: RxContext, bytes: &[u8]) {
+    let Some(command) = Command::parse(bytes) else {
+        return;
+    };
+    let dry_run = match command.mode {
+        BALANCING_MODE_NORMAL => false,
+        BALANCING_MODE_DRY_RUN => true,
+        mode => {
+            defmt::warn!("Balancing mode {} unknown, command ignored", mode);
+            return;
+        }
+    };
+    let mut ltc_data = ctx.ltc.lock().await;
+    ltc_data.set_balance_dry_run(dry_run);
+    drop(ltc_data);
+
+    let shutdown: bool = *ctx.is_shutdown.lock().await;
+    let mut is_balance_data = ctx.is_balance.lock().await;
+    *is_balance_data = command.enable && !shutdown;
+    drop(is_balance_data);
+}
+
+// Command layout (see Command). Mode 0: cells in Tech1..Tech3, 1: cells in one TechDelta frame.
+// Parameter reserved, 0
+async fn rx_tech(ctx: RxContext, bytes: &[u8]) {
+    let Some(command) = Command::parse(bytes) else {
+        return;
+    };
+    let delta = match command.mode {
+        TECH_MODE_CELLS => false,
+        TECH_MODE_DELTA => true,
+        mode => {
+            defmt::warn!("Tech mode {} unknown, command ignored", mode);
+            return;
+        }
+    };
+    let mut is_tech_data = ctx.is_tech.lock().await;
+    *is_tech_data = command.enable;
+    drop(is_tech_data);
+    TECH_DELTA.store(delta, Ordering::Relaxed);
+}
+
+async fn rx_run_self_test(ctx: RxContext) {
+    let mut ltc_data = ctx.ltc.lock().await;
+    let report = ltc_data.run_diagnostics().await;
+    drop(ltc_data);
+
+    let mut can_data = ctx.can.lock().await;
+    if can_diagnostics(&report, &mut can_data).await.is_err() {
+        defmt::error!("Failed to send self test result");
+    }
+    drop(can_data);
+}
+
+// Bytes 0-1: seconds of balancing regardless of check_need_balance, 0 cancels.
+// Faults and shutdown still abort it, the discharge bitmap follows the usual epsilon
+async fn rx_force_balance(ctx: RxContext, bytes: &[u8]) {
+    if let (Some(&low), Some(&high)) = (bytes.first(), bytes.get(1)) {
+        let duration_s = u16::from_le_bytes([low, high]);
+        let shutdown: bool = *ctx.is_shutdown.lock().await;
+        if duration_s == 0 {
+            FORCE_BALANCE_UNTIL_MS.store(0, Ordering::Relaxed);
+            info!("Forced balancing cancelled");
+        } else if shutdown {
+            defmt::warn!("Forced balancing refused, shutting down");
+        } else {
+            let now = embassy_time::Instant::now().as_millis() as u32;
+            FORCE_BALANCE_UNTIL_MS.store(now.saturating_add(duration_s as u32 * 1000), Ordering::Relaxed);
+            ctx.ltc.lock().await.set_balance_dry_run(false);
+            defmt::warn!("Balancing forced for {} s", duration_s);
+        }
+    }
+}
+
+// Bytes 0-1: bitmap of the cells to bleed, 0 clears it. ltc_function drops it on any fault
+async fn rx_manual_discharge(ctx: RxContext, bytes: &[u8]) {
+    if let (Some(&low), Some(&high)) = (bytes.first(), bytes.get(1)) {
+        let bitmap = u16::from_le_bytes([low, high]);
+        let mut ltc_data = ctx.ltc.lock().await;
+        let _ = if bitmap == 0 {
+            ltc_data.clear_manual_discharge().await
+        } else {
+            ltc_data.set_manual_discharge(bitmap).await
+        };
+        drop(ltc_data);
+    }
+}
+
+// Byte 0: cell index, answered with the CellHistory frames of that cell
+async fn rx_request_cell_history(ctx: RxContext, bytes: &[u8]) {
+    match bytes.first() {
+        Some(&cell) if (cell as usize) < NUM_CELLS => {
+            let history = ctx.bms.lock().await.cell_history(cell as usize);
+            let mut can_data = ctx.can.lock().await;
+            if can_cell_history(cell, &history, &mut can_data).await.is_err() {
+                defmt::error!("Failed to send cell {} history", cell);
             }
             drop(can_data);
-            drop(bms_data);
         }
-        embassy_time::Timer::after_millis(189).await;
+        _ => defmt::warn!("Cell history requested for an invalid cell"),
+    }
+}
+
+// Bytes 0-1: pack floor in 10mV, 0 restores MIN_PACK_VOLTAGE
+fn rx_set_pack_min_voltage(bytes: &[u8]) {
+    if let (Some(&low), Some(&high)) = (bytes.first(), bytes.get(1)) {
+        let raw = u16::from_le_bytes([low, high]);
+        let limit = match raw {
+            0 => MIN_PACK_VOLTAGE,
+            limit => limit as u32 * 100,
+        };
+        PACK_MIN_VOLTAGE.store(limit, Ordering::Relaxed);
+        PACK_MIN_SET.store(raw != 0, Ordering::Relaxed);
+        info!("Pack under-voltage floor set to {} (100uV)", limit);
+    }
+}
+
+// Byte 0: wired cells, byte 1: wired thermistors, both counted from the first input.
+// Out of range counts are refused and the topology in use is kept. An accepted one is saved to the
+// backup SRAM and restored at boot by setup_bms
+async fn rx_set_topology(ctx: RxContext, bytes: &[u8]) {
+    let (Some(&cells), Some(&thermistors)) = (bytes.first(), bytes.get(1)) else {
+        return;
+    };
+    match Topology::new(cells, thermistors) {
+        Some(topology) => {
+            ctx.bms.lock().await.set_topology(topology);
+            let mut record = backup::load().unwrap_or(backup::Persisted::new());
+            record.set_topology(topology);
+            backup::store(&record);
+            info!("Topology set to {}", topology);
+        }
+        None => defmt::warn!("Topology {} cells, {} thermistors refused, max {} and {}", cells, thermistors, NUM_CELLS, NUM_TERMISTORS),
+    }
+}
+
+// Answered with the two Thresholds frames: the limits actually in force, and which came from a command
+async fn rx_get_thresholds(ctx: RxContext) {
+    let max_temp = ctx.bms.lock().await.snapshot().max_temp;
+    let bal_epsilon = ctx.ltc.lock().await.balance_config().epsilon(max_temp);
+    let thresholds = Thresholds {
+        min_volt: VOLTAGES::MINVOLTAGE.value(),
+        max_volt: VOLTAGES::MAXVOLTAGE.value(),
+        pack_floor: PACK_MIN_VOLTAGE.load(Ordering::Relaxed),
+        min_temp: TEMPERATURES::MINTEMP.value(),
+        max_temp: TEMPERATURES::MAXTEMP.value(),
+        bal_epsilon,
+        from_command: if PACK_MIN_SET.load(Ordering::Relaxed) {THRESHOLD_PACK_FLOOR} else {0},
+    };
+    let mut can_data = ctx.can.lock().await;
+    if can_thresholds(&thresholds, &mut can_data).await.is_err() {
+        defmt::error!("Failed to send thresholds");
+    }
+    drop(can_data);
+}
 
+// Byte 0: bit0 clear the counters once read. Answered with the DiagnosticCounters frames,
+// read and clear is a single swap per counter so no event falls between the two
+async fn rx_get_diagnostics(ctx: RxContext, bytes: &[u8]) {
+    let clear = bytes.first().is_some_and(|flags| flags & 0x01 != 0);
+    let counts = if clear { DIAGNOSTICS.clear() } else { DIAGNOSTICS.snapshot() };
+    let mut can_data = ctx.can.lock().await;
+    if can_diagnostic_counters(&counts, &mut can_data).await.is_err() {
+        defmt::error!("Failed to send diagnostic counters");
     }
+    drop(can_data);
+    if clear {
+        info!("Diagnostic counters cleared over CAN");
+    }
+}
+
+// Recover a misbehaving LTC6811 without power cycling the board
+async fn rx_reset_ltc(ctx: RxContext) {
+    let mut ltc_data = ctx.ltc.lock().await;
+    let result = ltc_data.reset_ltc().await;
+    drop(ltc_data);
+    match result {
+        Ok(_) => info!("LTC6811 reset, configuration verified"),
+        Err(_) => defmt::error!("LTC6811 reset failed, configuration did not read back"),
+    }
+    LTC_RESET.signal(());
+}
+
+// Bytes 0-1: fault bits as in ErrorId, forced for bytes 2-3 x 100ms, 0 ends the injection.
+// They go through the same path as real faults: ErrorId, debug LED, err_check, balancing abort
+#[cfg(feature = "fault-injection")]
+fn rx_inject_fault(bytes: &[u8]) {
+    if let (Some(&low), Some(&high)) = (bytes.first(), bytes.get(1)) {
+        let faults = u16::from_le_bytes([low, high]);
+        let duration_ms = u16::from_le_bytes([bytes.get(2).copied().unwrap_or(0), bytes.get(3).copied().unwrap_or(0)]) as u32 * 100;
+        let now = embassy_time::Instant::now().as_millis() as u32;
+        INJECTED_FAULTS.store(faults, Ordering::Relaxed);
+        INJECTED_UNTIL_MS.store(now.saturating_add(duration_ms), Ordering::Relaxed);
+        defmt::warn!("Injecting faults {:#06x} for {} ms", faults, duration_ms);
+    }
+}
+
+// Safe state until power is lost: balancing off and err_check held at the fault level by ltc_function.
+// The PEC error count and the peaks go to the backup SRAM first, see backup: the save itself is a
+// few us, the worst case is waiting for the SLAVEBMS lock, held for a snapshot at most (<100us).
+// SOC is not tracked, so not saved. Stopping the balancing then waits up to one ltc_function
+// cycle for the LTC lock plus a WRCFGA (<1ms).
+// The log frames are only queued, USB may not drain them before the rails collapse.
+async fn rx_prepare_shutdown(ctx: RxContext) {
+    *ctx.is_shutdown.lock().await = true;
+    *ctx.is_balance.lock().await = false;
+    FORCE_BALANCE_UNTIL_MS.store(0, Ordering::Relaxed);
+
+    let bms_data = ctx.bms.lock().await;
+    let snapshot = bms_data.snapshot();
+    let peaks = bms_data.peaks();
+    drop(bms_data);
+    let mut record = backup::load().unwrap_or(backup::Persisted::new());
+    record.shutdown.pec_errors = DIAGNOSTICS.snapshot()[Counter::PecError as usize];
+    record.shutdown.peaks = peaks;
+    record.shutdown.saved = 1;
+    backup::store(&record);
+
+    let mut ltc_data = ctx.ltc.lock().await;
+    ltc_data.stop_balancing(BalancingState::Inactive).await;
+    drop(ltc_data);
+
+    defmt::warn!("Shutdown requested, entering safe state, saved to backup SRAM");
+    log_snapshot(&snapshot);
+}
+
+// What the last PrepareShutdown saved, at boot. Raw units like log_config
+fn log_saved_shutdown(record: &backup::Persisted) {
+    let shutdown = &record.shutdown;
+    if shutdown.saved == 0 {
+        return;
+    }
+    let peaks = &shutdown.peaks;
+    info!(
+        "Last shutdown: {} PEC errors, cells {}..{}, max temp {}, current {}..{}",
+        shutdown.pec_errors, peaks.min_volt.into_raw(), peaks.max_volt.into_raw(), peaks.max_temp.into_raw(),
+        peaks.max_charge.into_raw(), peaks.max_discharge.into_raw()
+    );
 }
 
 #[embassy_executor::task]
 async fn read_can(
     is_balance: &'static Mutex<CriticalSectionRawMutex, bool>,
     can: &'static Mutex<CriticalSectionRawMutex, CanController<'static>>,
-    is_tech: &'static Mutex<CriticalSectionRawMutex, bool>
+    is_tech: &'static Mutex<CriticalSectionRawMutex, bool>,
+    ltc: &'static Mutex<CriticalSectionRawMutex, Ltc>,
+    bms: &'static Mutex<CriticalSectionRawMutex, SLAVEBMS>,
+    is_shutdown: &'static Mutex<CriticalSectionRawMutex, bool>
 ){
+    let ctx = RxContext { is_balance, can, is_tech, ltc, bms, is_shutdown };
+    let mut time_bus_error_log = embassy_time::Instant::now().as_millis();
+    // BusOff is reported on every read while it lasts, count the entries only
+    let mut bus_off = false;
+
     loop {
         let mut can_data = can.lock().await;
         match can_data.read().await {
             Ok(frame) => {
                 let id = frame.id();
-                let bytes = frame.bytes();
                 drop(can_data);
-                if id == CanMsg::Balancing.as_raw() {
-                    if bytes[0] >= 0x1 as u8 {
-                        let mut is_balance_data = is_balance.lock().await;
-                        *is_balance_data = true;
-                        drop(is_balance_data);
-
-                    } else if bytes[0] == 0x0 as u8 {
-                        let mut is_balance_data = is_balance.lock().await;
-                        *is_balance_data = false;
-                        drop(is_balance_data);
-                    }
+                bus_off = false;
+                LAST_CAN_RX_MS.store(embassy_time::Instant::now().as_millis() as u32, Ordering::Relaxed);
+                exit_low_power("CAN traffic");
+                defmt::debug!("CAN RX {:#x} dlc {} seq {} at {} ms", id, frame.len(), frame.sequence(), frame.timestamp().as_millis());
+                match CanMsg::from_rx_id(id) {
+                    Some(msg) => dispatch_rx(ctx, msg, frame.payload()).await,
+                    None => rx_unhandled(id),
                 }
-                if id == CanMsg::Tech.as_raw() {
-                    if bytes[0] >= 0x1 as u8 {
-                        let mut is_tech_data = is_tech.lock().await;
-                        *is_tech_data = true;
-                        drop(is_tech_data);
-
-                    } else if bytes[0] == 0x0 as u8 {
-                        let mut is_tech_data = is_tech.lock().await;
-                        *is_tech_data = false;
-                        drop(is_tech_data);
-                    }
+            }
+            Err(CanError::NoItem) => {
+                drop(can_data);
+            }
+            Err(CanError::Overrun) => {
+                let overruns = can_data.rx_overruns();
+                drop(can_data);
+                defmt::warn!("CAN RX overrun, {} since boot", overruns);
+            }
+            Err(CanError::BusError(e)) => {
+                drop(can_data);
+                let entered_bus_off = matches!(e, BusError::BusOff);
+                if entered_bus_off && !bus_off {
+                    DIAGNOSTICS.bump(Counter::CanBusOff);
+                }
+                bus_off = entered_bus_off;
+                if embassy_time::Instant::now().as_millis() - time_bus_error_log > 1000 {
+                    defmt::warn!("CAN bus error: {}", e);
+                    time_bus_error_log = embassy_time::Instant::now().as_millis();
                 }
+                // Controller is reporting bus errors, give it some room
+                embassy_time::Timer::after_millis(1).await;
             }
             Err(_) => {
                 drop(can_data);
@@ -235,143 +1055,506 @@ async fn read_can(
 #[embassy_executor::task]
 async fn ltc_function(
     bms: &'static Mutex<CriticalSectionRawMutex, SLAVEBMS>, 
-    ltc: &'static Mutex<CriticalSectionRawMutex, LTC6811>,
+    ltc: &'static Mutex<CriticalSectionRawMutex, Ltc>,
     err_check: &'static Mutex<CriticalSectionRawMutex, Output<'static>>,
-    can: &'static Mutex<CriticalSectionRawMutex, CanController<'static>>,
     mut debug_led: Output<'static>,
     mut voltage_led: Output<'static>,
     mut temp_led: Output<'static>,
-    is_balance: &'static Mutex<CriticalSectionRawMutex, bool>
+    is_balance: &'static Mutex<CriticalSectionRawMutex, bool>,
+    is_shutdown: &'static Mutex<CriticalSectionRawMutex, bool>
 ) {
     let mut time_err_volt = embassy_time::Instant::now().as_millis();
     let mut time_err_temp = embassy_time::Instant::now().as_millis();
     let mut fault_temp: bool = false;
+    // A range fault the TEMP_WARMUP_READS warm-up is holding back, already logged
+    let mut temp_held_back = false;
     let mut fault_volt: bool = false;
-    let mut first_close = false;
+    // One bit per reading, newest in bit 0, set when a cell of it was out of UV/OV range
+    let mut volt_window: u32 = 0;
+
+    // LTC6811 UV/OV comparator flags, cross-checked against our own thresholds
+    let mut time_err_flags = embassy_time::Instant::now().as_millis();
+    let mut fault_flags: bool = false;
+
+    // Cells read one by one disagreeing with the chip's sum of cells
+    let mut time_err_sum = embassy_time::Instant::now().as_millis();
+    let mut fault_sum: bool = false;
+
+    // Cell codes above IMPLAUSIBLE_VOLTAGE, broken reads rather than over-voltage
+    let mut time_err_read = embassy_time::Instant::now().as_millis();
+    let mut fault_read: bool = false;
+
+    // LTC6811 unreachable for LTC_MAX_FAILURES updates in a row
+    let mut ltc_failures: u32 = 0;
+    let mut fault_comm: bool = false;
+
+    // Some cell register group failing PEC for longer than STALE_CELLS_MAX_MS
+    let mut time_err_stale = embassy_time::Instant::now().as_millis();
+    let mut fault_stale: bool = false;
+
+    // Discharge above DISCHARGE_CURRENT_LIMIT or regen beyond CHARGE_CURRENT_LIMIT
+    let mut time_err_current = embassy_time::Instant::now().as_millis();
+    let mut fault_current: bool = false;
+    // Same past the soft DISCHARGE_CURRENT_WARN/CHARGE_CURRENT_WARN, only a warning
+    let mut time_warn_current = embassy_time::Instant::now().as_millis();
+    let mut warn_current: bool = false;
+    let mut time_warn_thermistor = embassy_time::Instant::now().as_millis();
+    let mut warn_thermistor: bool = false;
+    // Start of the current window for the sign check: direction, uptime and average cell then
+    let mut power_ref: Option<(CurrentDirection, u64, DeciMilliVolt)> = None;
+    let mut warn_current_sign: bool = false;
+
+    // Sensed pack voltage disagreeing with the sum of the cells by more than PACK_MISMATCH_LIMIT
+    let mut time_err_pack = embassy_time::Instant::now().as_millis();
+    let mut fault_pack: bool = false;
+
+    // Pack below PACK_MIN_VOLTAGE, independent of the per-cell UV in fault_volt
+    let mut time_err_pack_uv = embassy_time::Instant::now().as_millis();
+    let mut fault_pack_uv: bool = false;
+
+    // Latched until reset: a fast rising temperature opens the contactors even below MAXTEMP
+    let mut fault_gradient: bool = false;
+
+    // Faults raised at least once whose FAULT_RESPONSES entry latches them
+    let mut latched: u16 = 0;
 
     let mut time_send_log = embassy_time::Instant::now().as_millis();
+    #[cfg(feature = "config-log")]
+    let mut time_config_log = embassy_time::Instant::now().as_millis();
+    let mut time_led_blink = embassy_time::Instant::now().as_millis();
+    let mut ready = false;
+
+    // Start of the current quiet stretch and the average cell it started from, see IDLE_TIMEOUT_MS
+    let mut time_idle = embassy_time::Instant::now().as_millis();
+    let mut idle_avg_volt = DeciMilliVolt::default();
+    // Balancing was forced last cycle, so its end switches the LTC back to NORMAL
+    let mut was_forced = false;
+    // Highest cell above BAL_START_VOLTAGE, with BAL_START_HYSTERESIS before it counts as below again
+    let mut top_of_charge = false;
 
     loop {
+        // Latched faults (gradient) survive a reset, the debounced ones start over
+        if LTC_RESET.try_take().is_some() {
+            let now = embassy_time::Instant::now().as_millis();
+            ltc_failures = 0;
+            fault_comm = false;
+            fault_read = false;
+            time_err_read = now;
+            fault_sum = false;
+            time_err_sum = now;
+            fault_stale = false;
+            time_err_stale = now;
+            fault_flags = false;
+            time_err_flags = now;
+        }
+
         let mut ltc_data = ltc.lock().await;
 
-        match ltc_data.update().await {
-            Ok(_) => {},
+        let update_start = embassy_time::Instant::now();
+        let update_result = ltc_data.update().await;
+        ltc_data.record_update_time(update_start.elapsed().as_micros() as u32);
+        match update_result {
+            Ok(_) => {
+                UPDATE_SEQ.fetch_add(1, Ordering::Relaxed);
+                LAST_UPDATE_MS.store(embassy_time::Instant::now().as_millis() as u32, Ordering::Relaxed);
+                BMS_UPDATED.signal(());
+                fault_sum = false;
+                time_err_sum = embassy_time::Instant::now().as_millis();
+                fault_read = false;
+                time_err_read = embassy_time::Instant::now().as_millis();
+            },
+            Err(LtcError::ImplausibleCell(cells)) => {
+                // A read error, not an over-voltage: the reading is dropped and only a persistent one faults
+                if embassy_time::Instant::now().as_millis() - time_err_read > FAULT_DEBOUNCE_MS && !fault_read {
+                    defmt::error!("Implausible cell readings, cells {:#05x}", cells);
+                    fault_read = true;
+                }
+            },
+            Err(LtcError::SumOfCells) => {
+                if embassy_time::Instant::now().as_millis() - time_err_sum > FAULT_DEBOUNCE_MS && !fault_sum {
+                    defmt::error!("Cell readings don't match the sum of cells");
+                    fault_sum = true;
+                }
+            },
             Err(_) => {
                 defmt::error!("Failed to update battery data");
             }
         }
-        
-        let is_balance_data = is_balance.lock().await;
-        let balance: bool = *is_balance_data;
-        drop(is_balance_data);
-        if balance == true{
-            for _ in 0..5 {
-                match ltc_data.update().await {
-                    Ok(_) => {},
-                    Err(_) => {
-                        defmt::error!("Failed to update battery data");
-                    }
-                }
+        if let Err(LtcError::Read) = update_result {
+            ltc_failures = ltc_failures.saturating_add(1);
+            if ltc_failures >= LTC_MAX_FAILURES && !fault_comm {
+                defmt::error!("LTC communication lost, {} failed updates", ltc_failures);
+                fault_comm = true;
             }
+        } else {
+            ltc_failures = 0;
+            fault_comm = false;
         }
-
+        
+        let hw_flags = ltc_data.read_voltage_flags().await;
+        let stale_thermistors = ltc_data.stale_thermistors();
+        let stale_cells = ltc_data.stale_cells();
+        let temps_settled = ltc_data.temps_settled();
+        let die_temp = ltc_data.die_temp();
+        // Set by check_cs at init or on ResetLtc, not re-evaluated every update
+        let fault_cs = ltc_data.cs_fault();
+        let update_timing = ltc_data.update_timing();
         drop(ltc_data);
 
-        let bms_data = bms.lock().await;
-        if &bms_data.min_volt() < &VOLTAGES::MINVOLTAGE.as_raw() || &bms_data.max_volt() > &VOLTAGES::MAXVOLTAGE.as_raw(){
-            if embassy_time::Instant::now().as_millis() - time_err_volt > 450 {
-                voltage_led.set_high();
+        let snapshot = bms.lock().await.snapshot();
+        // Channels left unwired by the topology read as shorted or open, they never fault
+        let cell_mask = snapshot.topology.cell_mask();
+        let hw_flags = hw_flags.map(|(uv, ov)| (uv & cell_mask, ov & cell_mask));
+        let stale_cells = stale_cells & cell_mask;
+        let stale_thermistors = stale_thermistors & snapshot.topology.temp_mask();
+        // Startup warm-up has no timer, it ends once SLAVEBMS::valid(): the zeroed history would read
+        // as UV until then. So the UV/OV and temperature checks don't run and err_check stays at the
+        // fault level (see below). ErrorId goes out from boot with the other faults, the measurement
+        // frames wait for LTC_READY
+        let valid = snapshot.valid;
+        // The averaged min/max are for display, the trip looks at the worst cells of each new
+        // reading, ours and the chip's UV/OV flags, and needs VOLT_TRIP_SAMPLES of the last
+        // VOLT_TRIP_WINDOW readings out of range on either side: a glitch in one can't trip it
+        let latest_min = snapshot.cell_volts[snapshot.min_cell as usize];
+        let latest_max = snapshot.cell_volts[snapshot.max_cell as usize];
+        let sw_volt_out = valid && (latest_min < VOLTAGES::MINVOLTAGE.value() || latest_max > VOLTAGES::MAXVOLTAGE.value());
+        let hw_volt_out = match hw_flags {
+            Ok((uv, ov)) => valid && (uv != 0 || ov != 0),
+            Err(_) => sw_volt_out, // flags unreadable, rely on our own check
+        };
+        if update_result.is_ok() {
+            let out_of_range = sw_volt_out || hw_volt_out;
+            volt_window = ((volt_window << 1) | out_of_range as u32) & (u32::MAX >> (32 - VOLT_TRIP_WINDOW));
+        }
+
+        if valid && volt_window.count_ones() >= VOLT_TRIP_SAMPLES {
+            if embassy_time::Instant::now().as_millis() - time_err_volt > FAULT_DEBOUNCE_MS {
                 fault_volt = true;
             }
         } else {
             fault_volt = false;
-            first_close = true;
             time_err_volt = embassy_time::Instant::now().as_millis();
         }
 
-        if &bms_data.min_temp() < &TEMPERATURES::MINTEMP._as_raw() || &bms_data.max_temp() > &TEMPERATURES::MAXTEMP._as_raw() {
-            if embassy_time::Instant::now().as_millis() - time_err_temp > 450 {
-                temp_led.set_high();
-                fault_temp = false;
+        // Chip and software disagreeing for longer than the debounce points to a bad read
+        if sw_volt_out != hw_volt_out {
+            if embassy_time::Instant::now().as_millis() - time_err_flags > FAULT_DEBOUNCE_MS && !fault_flags {
+                defmt::error!("LTC6811 UV/OV flags disagree with measured cells");
+                fault_flags = true;
+            }
+        } else {
+            fault_flags = false;
+            time_err_flags = embassy_time::Instant::now().as_millis();
+        }
+
+        let temp_out_of_range = valid && (snapshot.min_temp < TEMPERATURES::MINTEMP.value() || snapshot.max_temp > TEMPERATURES::MAXTEMP.value());
+        // During the warm-up the debounce doesn't even start, logged once so a held back trip is visible
+        if temp_out_of_range && !temps_settled {
+            if !temp_held_back {
+                defmt::warn!("Cell temperature out of range during warm-up, fault held back");
+                temp_held_back = true;
+            }
+        } else {
+            temp_held_back = false;
+        }
+        if temp_out_of_range && temps_settled {
+            if embassy_time::Instant::now().as_millis() - time_err_temp > FAULT_DEBOUNCE_MS && !fault_temp {
+                defmt::error!("Cell temperature out of range, min {} max {}", snapshot.min_temp.into_raw(), snapshot.max_temp.into_raw());
+                fault_temp = true;
             }
         } else {
             fault_temp = false;
-            first_close = true;
             time_err_temp = embassy_time::Instant::now().as_millis();
-            temp_led.set_low();
         }
 
-        if embassy_time::Instant::now().as_millis() - time_send_log > 1000 {
-            for i in 0..12 {
-                info!("Cell {}: {} mV", i, roundf(bms_data.cell_volts(i) as f32 /10f32));
-                embassy_time::Timer::after_millis(1).await;
+        // Cells of a failing register group are skipped and the rest stays monitored,
+        // but not for longer than STALE_CELLS_MAX_MS
+        if stale_cells != 0 {
+            if embassy_time::Instant::now().as_millis() - time_err_stale > STALE_CELLS_MAX_MS && !fault_stale {
+                defmt::error!("Cells {:#05x} stale for too long", stale_cells);
+                fault_stale = true;
             }
+        } else {
+            fault_stale = false;
+            time_err_stale = embassy_time::Instant::now().as_millis();
+        }
 
-            for i in 0..4{
-                info!("Temp {}: {} C", i, roundf(bms_data.temps(i) as f32 /10f32));
-                embassy_time::Timer::after_millis(1).await;
+        // Thermistors well below the LTC die at rest, see THERMISTOR_DIE_MAX_DELTA.
+        // Open sensors (u16::MAX) and stale ones are already reported elsewhere
+        let cold_thermistors = match die_temp {
+            Some(die) if valid && temps_settled && snapshot.current_direction() == CurrentDirection::Rest => snapshot
+                .temps
+                .iter()
+                .enumerate()
+                .filter(|&(i, temp)| {
+                    stale_thermistors & (1 << i) == 0
+                        && snapshot.topology.temp_mask() & (1 << i) != 0
+                        && temp.into_raw() != u16::MAX
+                        && die.into_raw().saturating_sub(temp.into_raw()) > THERMISTOR_DIE_MAX_DELTA.into_raw()
+                })
+                .fold(0u8, |bits, (i, _)| bits | (1 << i)),
+            _ => 0,
+        };
+        if cold_thermistors != 0 {
+            if embassy_time::Instant::now().as_millis() - time_warn_thermistor > THERMISTOR_DIE_DEBOUNCE_MS && !warn_thermistor {
+                defmt::warn!("Thermistors {:#06b} far below the die temperature {}", cold_thermistors, die_temp);
+                warn_thermistor = true;
+            }
+        } else {
+            warn_thermistor = false;
+            time_warn_thermistor = embassy_time::Instant::now().as_millis();
+        }
+
+        // Current sign against the cell trend, see POWER_CHECK_MIN_CURRENT. A window restarts
+        // whenever the current drops below the minimum or changes direction
+        let now_ms = embassy_time::Instant::now().as_millis();
+        let direction = snapshot.current_direction();
+        if !valid || snapshot.current.abs() < POWER_CHECK_MIN_CURRENT {
+            power_ref = None;
+        } else {
+            match power_ref {
+                Some((ref_direction, ref_ms, ref_volt)) if ref_direction == direction => {
+                    if now_ms - ref_ms >= POWER_CHECK_WINDOW_MS {
+                        let wrong_way = match direction {
+                            CurrentDirection::Discharge => snapshot.avg_volt.saturating_sub(ref_volt),
+                            _ => ref_volt.saturating_sub(snapshot.avg_volt),
+                        };
+                        let implausible = wrong_way > POWER_CHECK_WRONG_WAY;
+                        if implausible && !warn_current_sign {
+                            defmt::warn!("Current {} but the average cell moved {} the other way, sensor sign?", snapshot.current, wrong_way);
+                        }
+                        warn_current_sign = implausible;
+                        power_ref = Some((direction, now_ms, snapshot.avg_volt));
+                    }
+                }
+                _ => power_ref = Some((direction, now_ms, snapshot.avg_volt)),
             }
+        }
+
+        // Soft stage first: the driver is told to back off well before the hard trip
+        if snapshot.current > DISCHARGE_CURRENT_WARN || snapshot.current < CHARGE_CURRENT_WARN {
+            if embassy_time::Instant::now().as_millis() - time_warn_current > CURRENT_WARN_DEBOUNCE_MS && !warn_current {
+                defmt::warn!("Current {} past the soft limit", snapshot.current);
+                warn_current = true;
+            }
+        } else {
+            warn_current = false;
+            time_warn_current = embassy_time::Instant::now().as_millis();
+        }
 
-            info!("Fault Temp: {}\nFault Cells: {}", if fault_temp {"YES"} else {"NO"}, if fault_volt {"YES"} else {"NO"});
+        // Both directions trip the same way, regen above the charge limit is as bad as a discharge overcurrent
+        if snapshot.current > DISCHARGE_CURRENT_LIMIT || snapshot.current < CHARGE_CURRENT_LIMIT {
+            if embassy_time::Instant::now().as_millis() - time_err_current > CURRENT_TRIP_DEBOUNCE_MS && !fault_current {
+                defmt::error!("Over-current {} ({})", snapshot.current, if snapshot.current > DeciMilliAmp::default() {"discharge"} else {"charge"});
+                fault_current = true;
+            }
+        } else {
+            fault_current = false;
+            time_err_current = embassy_time::Instant::now().as_millis();
+        }
+
+        // Only comparable when every cell is in tot_volt
+        let pack_mismatch = match snapshot.pack_volt {
+            Some(pack_volt) if valid && stale_cells == 0 => pack_volt.abs_diff(snapshot.tot_volt) > PACK_MISMATCH_LIMIT,
+            _ => false,
+        };
+        if pack_mismatch {
+            if embassy_time::Instant::now().as_millis() - time_err_pack > FAULT_DEBOUNCE_MS && !fault_pack {
+                defmt::error!("Pack voltage {} doesn't match cell sum {} (100uV)", snapshot.pack_volt, snapshot.tot_volt);
+                fault_pack = true;
+            }
+        } else {
+            fault_pack = false;
+            time_err_pack = embassy_time::Instant::now().as_millis();
+        }
+
+        // A stale cell is missing from tot_volt, which would read as a pack under-voltage
+        if valid && stale_cells == 0 && snapshot.tot_volt < PACK_MIN_VOLTAGE.load(Ordering::Relaxed) {
+            if embassy_time::Instant::now().as_millis() - time_err_pack_uv > FAULT_DEBOUNCE_MS && !fault_pack_uv {
+                defmt::error!("Pack voltage {} below floor (100uV)", snapshot.tot_volt);
+                fault_pack_uv = true;
+            }
+        } else {
+            fault_pack_uv = false;
+            time_err_pack_uv = embassy_time::Instant::now().as_millis();
+        }
+
+        // Current ADC channel stuck on one code, current_sense decides, see CURRENT_FROZEN_WINDOWS
+        let fault_current_adc = CURRENT_FROZEN.load(Ordering::Relaxed);
+
+        if !fault_gradient && temps_settled && snapshot.max_temp_rate > MAX_TEMP_RATE {
+            defmt::error!("Temperature rising at {} (0.1 C/s), latching fault", snapshot.max_temp_rate);
+            fault_gradient = true;
+        }
+
+        if embassy_time::Instant::now().as_millis() - time_send_log > LOG_PERIOD_MS {
+            log_snapshot(&snapshot);
+            if stale_thermistors != 0 {
+                defmt::warn!("Stale thermistors {:#06b}", stale_thermistors);
+            }
+            info!(
+                "LTC update us last {} avg {} max {}",
+                update_timing.last_us, update_timing.avg_us, update_timing.max_us
+            );
+
+            info!("Fault Temp: {}\nFault Cells: {}\nFault Flags: {}\nFault Gradient: {}\nFault Sum: {}\nFault Read: {}\nFault Current: {}\nFault Comm: {}\nFault Stale Cells: {}\nFault Pack: {}\nFault Current ADC: {}\nFault Pack UV: {}\nFault SPI CS: {}", if fault_temp {"YES"} else {"NO"}, if fault_volt {"YES"} else {"NO"}, if fault_flags {"YES"} else {"NO"}, if fault_gradient {"YES"} else {"NO"}, if fault_sum {"YES"} else {"NO"}, if fault_read {"YES"} else {"NO"}, if fault_current {"YES"} else {"NO"}, if fault_comm {"YES"} else {"NO"}, if fault_stale {"YES"} else {"NO"}, if fault_pack {"YES"} else {"NO"}, if fault_current_adc {"YES"} else {"NO"}, if fault_pack_uv {"YES"} else {"NO"}, if fault_cs {"YES"} else {"NO"});
             embassy_time::Timer::after_millis(2).await;
             time_send_log = embassy_time::Instant::now().as_millis();
         }
+        #[cfg(feature = "config-log")]
+        if embassy_time::Instant::now().as_millis() - time_config_log > config::CONFIG_LOG_PERIOD_MS {
+            log_config();
+            time_config_log = embassy_time::Instant::now().as_millis();
+        }
         
-        drop(bms_data);
+        if valid && !ready {
+            LTC_READY.signal(());
+            ready = true;
+        }
 
-        let mut err_check_data = err_check.lock().await;
-        if !(fault_temp || fault_volt) {
-            if embassy_time::Instant::now().as_millis() > 1000 {
-                err_check_data.set_high();
-            }
-            debug_led.set_low();
+        let faults = (fault_temp as u16 * FAULT_TEMP)
+            | (fault_volt as u16 * FAULT_VOLT)
+            | (fault_flags as u16 * FAULT_FLAGS)
+            | (fault_gradient as u16 * FAULT_GRADIENT)
+            | (fault_sum as u16 * FAULT_SUM)
+            | (fault_read as u16 * FAULT_READ)
+            | (fault_current as u16 * FAULT_CURRENT)
+            | (fault_comm as u16 * FAULT_COMM)
+            | (fault_stale as u16 * FAULT_STALE)
+            | (fault_pack as u16 * FAULT_PACK)
+            | (fault_current_adc as u16 * FAULT_CURRENT_ADC)
+            | (fault_pack_uv as u16 * FAULT_PACK_UV)
+            | (fault_cs as u16 * FAULT_SPI_CS);
+        latched |= faults & LATCHED_FAULTS;
+        let faults = faults | latched;
+        let injected = injected_faults();
+        // The voltage LED stays lit once set, the temperature one follows its faults
+        if (faults | injected) & VOLTAGE_LED_FAULTS != 0 {
+            voltage_led.set_high();
+        }
+        if (faults | injected) & TEMP_LED_FAULTS != 0 {
+            temp_led.set_high();
         } else {
-            err_check_data.set_low();
-            if embassy_time::Instant::now().as_millis() > 2000 || first_close {
-                debug_led.toggle();
-                let mut can_data = can.lock().await;
-                let can_second = [
-                    1
-                ];
-
-                let frame_send = CanFrame::new(CanMsg::ErrorId.as_raw(), &can_second);
-                match can_data.write(&frame_send).await {
-                    Ok(_) => {}
-
-                    Err(CanError::Timeout) => {
-                        // info!("Timeout Can connection");
-                    }
+            temp_led.set_low();
+        }
+        let any_fault = (faults | injected) != 0;
+        let open_loop = (faults | injected) & OPEN_LOOP_FAULTS != 0;
+        FAULTS.store(faults | injected, Ordering::Relaxed);
+        WARNINGS.store(
+            (warn_current as u8 * WARN_CURRENT)
+                | (warn_thermistor as u8 * WARN_THERMISTOR)
+                | (warn_current_sign as u8 * WARN_CURRENT_SIGN),
+            Ordering::Relaxed,
+        );
 
-                    Err(_) => {
-                        // info!("Can write error");
-                    }
-                }
-                drop(can_data);
-                embassy_time::Timer::after_millis(200).await;
+        let shutdown: bool = *is_shutdown.lock().await;
+        let mut err_check_data = err_check.lock().await;
+        if shutdown {
+            set_err_check(&mut err_check_data, true);
+        } else if !open_loop {
+            if valid {
+                set_err_check(&mut err_check_data, false);
             }
+        } else {
+            set_err_check(&mut err_check_data, true);
         }
         drop(err_check_data);
 
+        if !any_fault {
+            debug_led.set_low();
+        } else if embassy_time::Instant::now().as_millis() - time_led_blink > FAULT_LED_BLINK_MS {
+            debug_led.toggle();
+            time_led_blink = embassy_time::Instant::now().as_millis();
+        }
+
         
+        // Balancing is refreshed once per cycle: update() mutes the discharge bits while measuring,
+        // so they are written again here and the fault checks above still run every cycle.
+        if !top_of_charge && snapshot.max_volt >= BAL_START_VOLTAGE {
+            top_of_charge = true;
+        } else if top_of_charge && snapshot.max_volt < BAL_START_VOLTAGE.saturating_sub(BAL_START_HYSTERESIS) {
+            top_of_charge = false;
+        }
+        // Commanded balancing waits for the top of charge and never runs while discharging,
+        // bleeding cells at low SOC only wastes energy. The command itself stays set.
+        // Inside the window the RestGate still wins: above BAL_CHARGE_CURRENT of charge the cells
+        // carry an IR drop, so balancing waits too instead of bleeding on a stale bitmap
+        let charge_window = valid && top_of_charge && snapshot.current_direction() != CurrentDirection::Discharge;
+
+        // ForceBalance skips the charge window, voltages_settled and check_need_balance, nothing else
         let mut is_balance_data = is_balance.lock().await;
-        let balance: bool = *is_balance_data;
-        if balance == true{
+        let forced = balance_forced();
+        let balance: bool = (*is_balance_data || forced) && !shutdown;
+        if balance {
             let mut ltc_data = ltc.lock().await;
-            if !ltc_data.check_need_balance().await {
+            if any_fault {
+                defmt::warn!("Balancing aborted, fault active");
                 *is_balance_data = false;
-            }
-            let time = embassy_time::Instant::now().as_millis();
-            while embassy_time::Instant::now().as_millis() - time < 10000 {
+                FORCE_BALANCE_UNTIL_MS.store(0, Ordering::Relaxed);
+                ltc_data.stop_balancing(BalancingState::StoppedFault).await;
+            } else if forced {
+                ltc_data.set_mode(MODE::BALANCING).await;
+            } else if !charge_window || !ltc_data.voltages_settled().await {
+                ltc_data.stop_balancing(BalancingState::Waiting).await;
+            } else if !ltc_data.check_need_balance().await {
+                *is_balance_data = false;
+                ltc_data.stop_balancing(BalancingState::StoppedBalanced).await;
+            } else {
                 ltc_data.set_mode(MODE::BALANCING).await;
-                embassy_time::Timer::after_millis(5).await;
             }
             drop(ltc_data);
+        } else if was_forced {
+            info!("Forced balancing over");
+            ltc.lock().await.stop_balancing(BalancingState::StoppedTimeout).await;
         } else {
-            embassy_time::Timer::after_millis(5).await;
+            // Switched off by command: nothing else takes the LTC out of BALANCING
+            let mut ltc_data = ltc.lock().await;
+            if matches!(ltc_data.balancing_state(), BalancingState::Active | BalancingState::Waiting) {
+                ltc_data.stop_balancing(BalancingState::Inactive).await;
+            }
+            drop(ltc_data);
         }
+        was_forced = forced && balance && !any_fault;
+
+        if any_fault || shutdown {
+            let mut ltc_data = ltc.lock().await;
+            if ltc_data.manual_discharge().is_some() {
+                defmt::warn!("Manual discharge cleared, fault active");
+                let _ = ltc_data.clear_manual_discharge().await;
+            }
+            drop(ltc_data);
+        }
+        let manual = ltc.lock().await.manual_discharge().is_some();
+        embassy_time::Timer::after_millis(5).await;
 
         drop(is_balance_data);
+
+        // Parked: nothing on the bus, no current, nothing to balance and cells not moving
+        let now = embassy_time::Instant::now().as_millis();
+        let quiet = valid
+            && !any_fault
+            && !balance
+            && !manual
+            && !shutdown
+            && snapshot.current.abs() <= REST_CURRENT
+            && (now as u32).wrapping_sub(LAST_CAN_RX_MS.load(Ordering::Relaxed)) as u64 > IDLE_TIMEOUT_MS
+            && snapshot.avg_volt.abs_diff(idle_avg_volt) <= IDLE_VOLT_DELTA;
+        if !quiet {
+            time_idle = now;
+            idle_avg_volt = snapshot.avg_volt;
+            exit_low_power("activity");
+        } else if now - time_idle > IDLE_TIMEOUT_MS && !LOW_POWER.swap(true, Ordering::Relaxed) {
+            info!("Pack idle for {} s, entering low power", IDLE_TIMEOUT_MS / 1000);
+            LOW_POWER_WAKE.reset();
+        }
+
         // info!("ALIVE");
-        embassy_time::Timer::after_millis(5).await;
+        if LOW_POWER.load(Ordering::Relaxed) {
+            // Long enough for the LTC6811 watchdog to put it in SLEEP, wake it before the next update
+            select(LOW_POWER_WAKE.wait(), embassy_time::Timer::after_millis(LOW_POWER_LTC_PERIOD_MS)).await;
+            ltc.lock().await.wake_from_sleep().await;
+        } else {
+            embassy_time::Timer::after_millis(5).await;
+        }
     }
 } 
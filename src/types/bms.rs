@@ -1,13 +1,22 @@
 use libm::roundf;
 
-pub static NUM_CELLS: usize = 12;
-pub static NUM_TERMISTORS: usize = 4;
-pub static NUM_HISTORY: usize = 5;
-
+pub use crate::config::{NUM_CELLS, NUM_TERMISTORS, NUM_HISTORY};
+use crate::config::{CURRENT_AVG_WINDOWS, HISTORY_PERIOD_MS, REST_CURRENT, TEMP_RATE_WINDOW_MS};
+use super::scaling::{pack_to_can, pack_to_mv};
+use super::units::{DeciCelsius, DeciMilliAmp, DeciMilliVolt};
+
+// History sequencing: push_reading writes a whole read cycle into one slot, then aggregates.
+// Slots advance on a fixed HISTORY_PERIOD_MS schedule, not per reading: the first reading of a
+// period takes the slot at `index` and moves `index` on, later ones in the same period overwrite
+// that slot again. Every slot then stands for one period and the average spans the same time
+// whatever the LTC loop rate. Nothing advances between two channels of the same cycle, so a
+// reading never straddles two slots. The getters of the latest values (cell_volts, temps) read
+// the last written slot
 #[derive(Default, Debug, Copy, Clone)]
 pub struct SLAVEBMS {
     bms_history: [BMS; NUM_HISTORY],
-    index: usize,
+    index: usize, // slot the next period starts in, also the oldest one
+    last_sample_ms: Option<u64>, // when the current period started, see HISTORY_PERIOD_MS
     tot_volt: u32,
     max_volt: u16,
     min_volt: u16,
@@ -15,17 +24,155 @@ pub struct SLAVEBMS {
     max_temp: u16,
     min_temp: u16,
     avg_temp: u16,
-    current: i32
+    current: i32,     // latest current_sense window, 100uA
+    current_avg: i32, // exponential average of `current` over CURRENT_AVG_WINDOWS
+    temp_ref: [u16; NUM_TERMISTORS],
+    temp_ref_ms: Option<u64>,
+    temp_rate: [i32; NUM_TERMISTORS],
+    samples: usize, // updates so far, saturating at NUM_HISTORY
+    raw: bool, // commissioning: aggregates follow the latest reading, no history average
+    pack_volt: Option<u32>, // independent pack voltage sense, 100uV like tot_volt, None before the first reading
+    min_cell: u8, // index of the lowest cell in the latest reading
+    max_cell: u8, // index of the highest cell in the latest reading
+    topology: Topology,
+    peaks: Peaks,
+}
+
+// Cells and thermistors wired on this segment: the first `cells` cell inputs and the first
+// `thermistors` thermistors. The others are left out of the aggregates, balancing and the
+// fault checks, like stale ones. Set with SetTopology, kept in the backup SRAM across resets
+#[derive(Debug, Copy, Clone, Eq, PartialEq, defmt::Format)]
+pub struct Topology {
+    pub cells: u8,
+    pub thermistors: u8,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Topology::FULL
+    }
+}
+
+impl Topology {
+    pub const FULL: Topology = Topology { cells: NUM_CELLS as u8, thermistors: NUM_TERMISTORS as u8 };
+
+    // None past the hardware (NUM_CELLS cell inputs, NUM_TERMISTORS thermistors) or with nothing left to measure
+    pub fn new(cells: u8, thermistors: u8) -> Option<Self> {
+        let cells_ok = (1..=NUM_CELLS as u8).contains(&cells);
+        let thermistors_ok = (1..=NUM_TERMISTORS as u8).contains(&thermistors);
+        (cells_ok && thermistors_ok).then_some(Topology { cells, thermistors })
+    }
+
+    // Bit i set = cell i is wired
+    pub fn cell_mask(&self) -> u16 {
+        (1 << self.cells) - 1
+    }
+
+    // Bit i set = thermistor i is wired
+    pub fn temp_mask(&self) -> u16 {
+        (1 << self.thermistors) - 1
+    }
+}
+
+// Everything readers need, copied out in one go so the SLAVEBMS lock is held briefly
+#[derive(Default, Debug, Copy, Clone)]
+pub struct BmsSnapshot {
+    pub tot_volt: u32, // 100uV, the pack range needs more than a cell's u16
+    pub max_volt: DeciMilliVolt,
+    pub min_volt: DeciMilliVolt,
+    pub avg_volt: DeciMilliVolt,
+    pub max_temp: DeciCelsius,
+    pub min_temp: DeciCelsius,
+    pub avg_temp: DeciCelsius,
+    pub max_temp_rate: i32, // 0.1 °C/s
+    pub current: DeciMilliAmp,     // fast, for the over-current trip
+    pub current_avg: DeciMilliAmp, // averaged, see CURRENT_AVG_WINDOWS
+    pub cell_volts: [DeciMilliVolt; NUM_CELLS],
+    pub temps: [DeciCelsius; NUM_TERMISTORS],
+    pub pack_volt: Option<u32>,
+    pub min_cell: u8,
+    pub max_cell: u8,
+    pub topology: Topology,
+    pub valid: bool,
+}
+
+// Sign of the pack current, anything within REST_CURRENT is rest
+#[derive(Debug, Copy, Clone, Eq, PartialEq, defmt::Format)]
+pub enum CurrentDirection {
+    Discharge,
+    Rest,
+    Charge,
+}
+
+impl BmsSnapshot {
+    pub fn current_direction(&self) -> CurrentDirection {
+        if self.current.abs() < REST_CURRENT {
+            CurrentDirection::Rest
+        } else if self.current > DeciMilliAmp::default() {
+            CurrentDirection::Discharge
+        } else {
+            CurrentDirection::Charge
+        }
+    }
+
+    // Sum of the fresh cells in mV, rounded
+    pub fn pack_voltage_mv(&self) -> u32 {
+        pack_to_mv(self.tot_volt)
+    }
+
+    // Sum of the fresh cells in the 10 mV of PackVoltage, truncated. Saturates at
+    // u16::MAX (655.35 V): a higher voltage pack reads as pinned at the top, it never wraps
+    pub fn pack_voltage_10mv(&self) -> u16 {
+        pack_to_can(self.tot_volt)
+    }
+}
+
+// Extremes since boot, from the latest readings rather than the averages, saved to the backup
+// SRAM by PrepareShutdown. repr(C) without padding, it is stored as bytes
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Peaks {
+    pub max_discharge: DeciMilliAmp, // most positive current
+    pub max_charge: DeciMilliAmp,    // most negative current
+    pub max_volt: DeciMilliVolt,
+    pub min_volt: DeciMilliVolt,     // u16::MAX until the first valid reading
+    pub max_temp: DeciCelsius,
+    _reserved: u16,
+}
+
+impl Peaks {
+    pub const fn new() -> Self {
+        Peaks {
+            max_discharge: DeciMilliAmp::new(0),
+            max_charge: DeciMilliAmp::new(0),
+            max_volt: DeciMilliVolt::new(0),
+            min_volt: DeciMilliVolt::new(u16::MAX),
+            max_temp: DeciCelsius::new(0),
+            _reserved: 0,
+        }
+    }
+}
+
+impl Default for Peaks {
+    fn default() -> Self {
+        Peaks::new()
+    }
 }
 
 #[derive(Default, Debug, Copy, Clone)]
 pub struct BMS {
     pub cell_volts: [u16; NUM_CELLS],
+    cell_stale: u16, // bit i set = cell_volts[i] is old and left out of the aggregates
+    cell_unused: u16, // bit i set = cell i is not wired (Topology), left out like a stale one
     tot_volt: u32,
     max_volt: u16,
     min_volt: u16,
     avg_volt: u16,
+    max_cell: u8, // index of max_volt
+    min_cell: u8, // index of min_volt
     pub temperatures: [u16; NUM_TERMISTORS],
+    temp_stale: u16, // bit i set = temperatures[i] is old and left out of min/max/avg
+    temp_unused: u16, // bit i set = thermistor i is not wired (Topology)
     max_temp: u16,
     min_temp: u16,
     avg_temp: u16,
@@ -35,37 +182,74 @@ impl BMS {
     pub fn new() -> Self {
         BMS {
             cell_volts: [0; NUM_CELLS],
+            cell_stale: 0,
+            cell_unused: 0,
             max_volt: 0,
             min_volt: 0,
             avg_volt: 0,
             tot_volt: 0,
+            max_cell: 0,
+            min_cell: 0,
             temperatures: [0; NUM_TERMISTORS],
+            temp_stale: 0,
+            temp_unused: 0,
             max_temp: 0,
             min_temp: 0,
             avg_temp: 0,
         }
     }
 
-    pub fn update_temp(&mut self, i: usize, value: u16) {
-        self.temperatures[i] = value;
-        self.update();
-    }
-
-    pub fn update_cell(&mut self, i: usize, value: u16) {
-        self.cell_volts[i] = value;
+    // A whole reading, one aggregate pass at the end. None keeps the old value, marked stale
+    fn set_reading(&mut self, cells: &[Option<u16>; NUM_CELLS], temps: &[Option<u16>; NUM_TERMISTORS]) {
+        for (i, cell) in cells.iter().enumerate() {
+            match cell {
+                Some(value) => {
+                    self.cell_volts[i] = *value;
+                    self.cell_stale &= !(1 << i);
+                }
+                None => self.cell_stale |= 1 << i,
+            }
+        }
+        for (i, temp) in temps.iter().enumerate() {
+            match temp {
+                Some(value) => {
+                    self.temperatures[i] = *value;
+                    self.temp_stale &= !(1 << i);
+                }
+                None => self.temp_stale |= 1 << i,
+            }
+        }
         self.update();
     }
 
     fn update(&mut self){
+        // Stale cells are left out: tot_volt only sums fresh cells, avg_volt averages them.
+        // Sums saturate, a garbage reading pins them high where the plausibility checks see it
         self.tot_volt = 0;
         self.max_volt = 0;
         self.min_volt = u16::MAX;
-        for &volt in self.cell_volts.iter() {
-            self.tot_volt = self.tot_volt.wrapping_add(volt as u32);
-            self.max_volt = if volt > self.max_volt {volt} else {self.max_volt};
-            self.min_volt = if volt < self.min_volt {volt} else {self.min_volt};
+        self.max_cell = 0;
+        self.min_cell = 0;
+        let mut fresh: u32 = 0;
+        for (i, &volt) in self.cell_volts.iter().enumerate() {
+            if (self.cell_stale | self.cell_unused) & (1 << i) != 0 {
+                continue;
+            }
+            fresh += 1;
+            self.tot_volt = self.tot_volt.saturating_add(volt as u32);
+            if volt > self.max_volt {
+                self.max_volt = volt;
+                self.max_cell = i as u8;
+            }
+            if volt < self.min_volt {
+                self.min_volt = volt;
+                self.min_cell = i as u8;
+            }
         }
-        let v_float = (self.tot_volt as f32) /(NUM_CELLS as f32);
+        if fresh == 0 {
+            self.min_volt = 0;
+        }
+        let v_float = (self.tot_volt as f32) /(fresh.max(1) as f32);
         let rounded: u16 = if v_float >= 0.0 {
             roundf(v_float).max(0.0) as u16
         } else {
@@ -75,15 +259,23 @@ impl BMS {
         self.avg_volt = rounded;
 
         let mut tot_temp: u32 = 0;
+        let mut fresh: u32 = 0;
         self.max_temp = 0;
         self.min_temp = u16::MAX;
-        for &temp in self.temperatures.iter() {
-            tot_temp = tot_temp.wrapping_add(temp as u32);
+        for (i, &temp) in self.temperatures.iter().enumerate() {
+            if (self.temp_stale | self.temp_unused) & (1 << i) != 0 {
+                continue;
+            }
+            fresh += 1;
+            tot_temp = tot_temp.saturating_add(temp as u32);
             self.max_temp = if temp > self.max_temp {temp} else {self.max_temp};
             self.min_temp = if temp < self.min_temp {temp} else {self.min_temp};
 
         }
-        let v_float = (tot_temp as f32) /((NUM_TERMISTORS -1) as f32);
+        if fresh == 0 {
+            self.min_temp = 0;
+        }
+        let v_float = (tot_temp as f32) /(fresh.max(1) as f32);
         let rounded: u16 = if v_float >= 0.0 {
             roundf(v_float).max(0.0) as u16
         } else {
@@ -111,6 +303,14 @@ impl BMS {
         self.max_volt
     }
 
+    pub fn min_cell(&self) -> u8 {
+        self.min_cell
+    }
+
+    pub fn max_cell(&self) -> u8 {
+        self.max_cell
+    }
+
     pub fn avg_temp(&self) -> u16 {
         self.avg_temp
     }
@@ -137,11 +337,72 @@ impl SLAVEBMS {
             max_temp: 0,
             min_temp: 0,
             avg_temp: 0,
-            current: 0
+            current: 0,
+            current_avg: 0,
+            temp_ref: [0; NUM_TERMISTORS],
+            temp_ref_ms: None,
+            temp_rate: [0; NUM_TERMISTORS],
+            samples: 0,
+            last_sample_ms: None,
+            raw: false,
+            pack_volt: None,
+            min_cell: 0,
+            max_cell: 0,
+            topology: Topology::FULL,
+            peaks: Peaks::new(),
+        }
+    }
+
+    // Every history slot holds the same readings, so the aggregates match them
+    // exactly without going through push_reading. For tests and replay
+    #[allow(unused)]
+    pub fn from_readings(cells: [u16; NUM_CELLS], temps: [u16; NUM_TERMISTORS]) -> Self {
+        let mut reading = BMS::new();
+        reading.cell_volts = cells;
+        reading.temperatures = temps;
+        reading.update();
+
+        let mut bms = SLAVEBMS {
+            bms_history: [reading; NUM_HISTORY],
+            samples: NUM_HISTORY,
+            ..SLAVEBMS::new()
+        };
+        bms.aggregate();
+        bms
+    }
+
+    // One full read cycle taken at `now_ms`, see the sequencing above. None marks the
+    // channel stale for this slot
+    pub fn push_reading(&mut self, cells: &[Option<DeciMilliVolt>; NUM_CELLS], temps: &[Option<DeciCelsius>; NUM_TERMISTORS], now_ms: u64) {
+        let due = self.last_sample_ms.is_none_or(|start| now_ms.saturating_sub(start) >= HISTORY_PERIOD_MS);
+        if due {
+            self.index = (self.index + 1) % NUM_HISTORY;
+            self.samples = (self.samples + 1).min(NUM_HISTORY);
+            // A late reading starts the next period from now, missed periods are not filled in
+            self.last_sample_ms = Some(match self.last_sample_ms {
+                Some(start) if now_ms - start < 2 * HISTORY_PERIOD_MS => start + HISTORY_PERIOD_MS,
+                _ => now_ms,
+            });
+        }
+        let slot = (self.index + NUM_HISTORY - 1) % NUM_HISTORY;
+        self.bms_history[slot].set_reading(
+            &cells.map(|cell| cell.map(DeciMilliVolt::into_raw)),
+            &temps.map(|temp| temp.map(DeciCelsius::into_raw)),
+        );
+        self.aggregate();
+        if self.valid() {
+            let latest = *self.latest();
+            self.peaks.max_volt = self.peaks.max_volt.max(DeciMilliVolt::new(latest.max_volt()));
+            self.peaks.min_volt = self.peaks.min_volt.min(DeciMilliVolt::new(latest.min_volt()));
+            // u16::MAX is a shorted thermistor, not a temperature
+            if latest.max_temp() != u16::MAX {
+                self.peaks.max_temp = self.peaks.max_temp.max(DeciCelsius::new(latest.max_temp()));
+            }
         }
     }
 
-    pub fn update(&mut self) {
+    // Aggregates over every slot, the latest values come from the last written one
+    fn aggregate(&mut self) {
         let mut tot_volt: u64 = 0;
         let mut max_volt: u64 = 0;
         let mut min_volt: u64 = 0;
@@ -151,13 +412,13 @@ impl SLAVEBMS {
         let mut avg_temp: u64 = 0;
 
         for &bms in self.bms_history.iter() {
-            tot_volt = tot_volt.wrapping_add(bms.tot_volt() as u64);
-            max_volt = max_volt.wrapping_add(bms.max_volt() as u64);
-            min_volt = min_volt.wrapping_add(bms.min_volt() as u64);
-            avg_volt = avg_volt.wrapping_add(bms.avg_volt() as u64);
-            max_temp = max_temp.wrapping_add(bms.max_temp() as u64);
-            min_temp = min_temp.wrapping_add(bms.min_temp() as u64);
-            avg_temp = avg_temp.wrapping_add(bms.avg_temp() as u64);
+            tot_volt = tot_volt.saturating_add(bms.tot_volt() as u64);
+            max_volt = max_volt.saturating_add(bms.max_volt() as u64);
+            min_volt = min_volt.saturating_add(bms.min_volt() as u64);
+            avg_volt = avg_volt.saturating_add(bms.avg_volt() as u64);
+            max_temp = max_temp.saturating_add(bms.max_temp() as u64);
+            min_temp = min_temp.saturating_add(bms.min_temp() as u64);
+            avg_temp = avg_temp.saturating_add(bms.avg_temp() as u64);
         }
 
         let tot_v_float: f32 = ((tot_volt as f64) /(NUM_HISTORY as f64) ) as f32; 
@@ -209,61 +470,216 @@ impl SLAVEBMS {
             0
         };
 
-        self.index = self.index + 1;
-        if self.index >= NUM_HISTORY {
-            self.index = 0;
+        // Averaging indices makes no sense, they come from the reading just completed
+        self.min_cell = self.latest().min_cell();
+        self.max_cell = self.latest().max_cell();
+
+        if self.raw {
+            let latest = *self.latest();
+            self.tot_volt = latest.tot_volt();
+            self.max_volt = latest.max_volt();
+            self.min_volt = latest.min_volt();
+            self.avg_volt = latest.avg_volt();
+            self.max_temp = latest.max_temp();
+            self.min_temp = latest.min_temp();
+            self.avg_temp = latest.avg_temp();
         }
     }
 
-    pub fn update_temp(&mut self, i: usize, value: u16) {
-        self.bms_history[self.index].update_temp(i, value);
+    // Data is not valid until every history slot holds a real reading,
+    // before that the averages are dragged towards 0
+    pub fn valid(&self) -> bool {
+        self.samples >= NUM_HISTORY
     }
 
-    pub fn update_cell(&mut self, i: usize, value: u16) {
-        self.bms_history[self.index].update_cell(i, value);
+    pub fn raw(&self) -> bool {
+        self.raw
     }
 
-    pub fn avg_volt(&self) -> u16 {
-        self.avg_volt
+    pub fn topology(&self) -> Topology {
+        self.topology
     }
 
-    pub fn tot_volt(&self) -> u32 {
+    // Every history slot is re-aggregated without the unwired channels, the pack aggregates
+    // follow on the next update()
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = topology;
+        for reading in self.bms_history.iter_mut() {
+            reading.cell_unused = !topology.cell_mask() & ((1 << NUM_CELLS) - 1);
+            reading.temp_unused = !topology.temp_mask() & ((1 << NUM_TERMISTORS) - 1);
+            reading.update();
+        }
+    }
+
+    // Only for spot checks against a multimeter: a single noisy reading can trip the limits
+    pub fn set_raw(&mut self, raw: bool) {
+        self.raw = raw;
+    }
+
+    pub fn avg_volt(&self) -> DeciMilliVolt {
+        DeciMilliVolt::new(self.avg_volt)
+    }
+
+    pub fn _tot_volt(&self) -> u32 {
         self.tot_volt
     }
 
-    pub fn min_volt(&self) -> u16 {
-        self.min_volt
+    pub fn min_volt(&self) -> DeciMilliVolt {
+        DeciMilliVolt::new(self.min_volt)
     }
 
-    pub fn max_volt(&self) -> u16 {
-        self.max_volt
+    pub fn max_volt(&self) -> DeciMilliVolt {
+        DeciMilliVolt::new(self.max_volt)
     }
 
-    pub fn _avg_temp(&self) -> u16 {
-        self.avg_temp
+    pub fn avg_temp(&self) -> DeciCelsius {
+        DeciCelsius::new(self.avg_temp)
     }
 
-    pub fn min_temp(&self) -> u16 {
-        self.min_temp
+    pub fn min_temp(&self) -> DeciCelsius {
+        DeciCelsius::new(self.min_temp)
     }
 
-    pub fn max_temp(&self) -> u16 {
-        self.max_temp
+    pub fn max_temp(&self) -> DeciCelsius {
+        DeciCelsius::new(self.max_temp)
+    }
+
+    // Slot of the last committed reading, the one before `index`
+    fn latest(&self) -> &BMS {
+        &self.bms_history[(self.index + NUM_HISTORY - 1) % NUM_HISTORY]
+    }
+
+    pub fn cell_volts(&self, i: usize) -> DeciMilliVolt {
+        DeciMilliVolt::new(self.latest().cell_volts[i])
+    }
+
+    // Raw history, `sample` counts from the oldest complete reading (0) to the newest
+    // (NUM_HISTORY - 1). The oldest sits at `index`, the next slot push_reading starts.
+    // None if either index is out of range
+    pub fn cell_volts_at(&self, sample: usize, cell: usize) -> Option<u16> {
+        if sample >= NUM_HISTORY {
+            return None;
+        }
+        self.bms_history[(self.index + sample) % NUM_HISTORY].cell_volts.get(cell).copied()
+    }
+
+    #[allow(unused)]
+    pub fn temp_at(&self, sample: usize, channel: usize) -> Option<u16> {
+        if sample >= NUM_HISTORY {
+            return None;
+        }
+        self.bms_history[(self.index + sample) % NUM_HISTORY].temperatures.get(channel).copied()
+    }
+
+    // Every sample of cell i, oldest first, 0 for an out of range cell
+    pub fn cell_history(&self, i: usize) -> [u16; NUM_HISTORY] {
+        let mut history = [0u16; NUM_HISTORY];
+        for (n, sample) in history.iter_mut().enumerate() {
+            *sample = self.cell_volts_at(n, i).unwrap_or(0);
+        }
+        history
+    }
+
+    pub fn temps(&self, i: usize) -> DeciCelsius {
+        DeciCelsius::new(self.latest().temperatures[i])
+    }
+
+    // Per thermistor rate of change in 0.1 °C/s. It is taken over at least TEMP_RATE_WINDOW_MS
+    // so noise on a single reading can't fake a gradient; open sensors (u16::MAX) read as 0
+    pub fn update_temp_rate(&mut self, temps: &[u16; NUM_TERMISTORS], now_ms: u64) {
+        let ref_ms = match self.temp_ref_ms {
+            Some(ref_ms) => ref_ms,
+            None => {
+                self.temp_ref = *temps;
+                self.temp_ref_ms = Some(now_ms);
+                return;
+            }
+        };
+
+        let dt = now_ms.saturating_sub(ref_ms);
+        if dt < TEMP_RATE_WINDOW_MS {
+            return;
+        }
+
+        for (i, rate) in self.temp_rate.iter_mut().enumerate() {
+            let unused = self.topology.temp_mask() & (1 << i) == 0;
+            *rate = if unused || temps[i] == u16::MAX || self.temp_ref[i] == u16::MAX {
+                0
+            } else {
+                (temps[i] as i32 - self.temp_ref[i] as i32) * 1000 / dt as i32
+            };
+        }
+        self.temp_ref = *temps;
+        self.temp_ref_ms = Some(now_ms);
+    }
+
+    pub fn _temp_rate(&self, i: usize) -> i32 {
+        self.temp_rate[i]
+    }
+
+    pub fn max_temp_rate(&self) -> i32 {
+        self.temp_rate.iter().copied().max().unwrap_or(0)
+    }
+
+    pub fn snapshot(&self) -> BmsSnapshot {
+        let mut cell_volts = [DeciMilliVolt::default(); NUM_CELLS];
+        for (i, cell) in cell_volts.iter_mut().enumerate() {
+            *cell = self.cell_volts(i);
+        }
+        let mut temps = [DeciCelsius::default(); NUM_TERMISTORS];
+        for (i, temp) in temps.iter_mut().enumerate() {
+            *temp = self.temps(i);
+        }
+
+        BmsSnapshot {
+            tot_volt: self.tot_volt,
+            max_volt: self.max_volt(),
+            min_volt: self.min_volt(),
+            avg_volt: self.avg_volt(),
+            max_temp: self.max_temp(),
+            min_temp: self.min_temp(),
+            avg_temp: self.avg_temp(),
+            max_temp_rate: self.max_temp_rate(),
+            current: self.current(),
+            current_avg: self.current_avg(),
+            cell_volts,
+            temps,
+            pack_volt: self.pack_volt,
+            min_cell: self.min_cell,
+            max_cell: self.max_cell,
+            topology: self.topology,
+            valid: self.valid(),
+        }
+    }
+
+    pub fn update_pack_volt(&mut self, value: u32) {
+        self.pack_volt = Some(value);
+    }
+
+    // Pack voltage from the dedicated sense, not the cell taps, in 100uV steps
+    pub fn _pack_volt(&self) -> Option<u32> {
+        self.pack_volt
     }
 
-    pub fn cell_volts(&self, i: usize) -> u16 {
-        self.bms_history[self.index].cell_volts[i]
+    pub fn update_current(&mut self, value: DeciMilliAmp) {
+        self.current = value.into_raw();
+        self.current_avg += (self.current - self.current_avg) / CURRENT_AVG_WINDOWS;
+        self.peaks.max_discharge = self.peaks.max_discharge.max(value);
+        self.peaks.max_charge = self.peaks.max_charge.min(value);
     }
 
-    pub fn temps(&self, i: usize) -> u16 {
-        self.bms_history[self.index].temperatures[i]
+    pub fn peaks(&self) -> Peaks {
+        self.peaks
     }
 
-    pub fn update_current(&mut self, value: i32) {
-        self.current = value;
+    // Last value from current_sense, one ~20ms window: the sensor gives 9.2mV/A and the
+    // reading is scaled by 10000. Catches transients, for the over-current trip
+    pub fn current(&self) -> DeciMilliAmp {
+        DeciMilliAmp::new(self.current)
     }
 
-    pub fn current(&self) -> i32 {
-        self.current
+    // current() averaged over about CURRENT_AVG_WINDOWS windows, for charge counting
+    pub fn current_avg(&self) -> DeciMilliAmp {
+        DeciMilliAmp::new(self.current_avg)
     }
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+// Health counters accumulated since boot or the last clear. All of them live here, so a
+// session can snapshot them at the start and read the deltas at the end over CAN or USB
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Index of each counter in DiagnosticCounters, the order is the wire order of the
+// DiagnosticCounters frames and of the USB dump. Append only, never renumber
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Counter {
+    PecError = 0,     // LTC6811 register group read back with a wrong PEC
+    CanRxOverrun = 1, // RX FIFO overflowed, frames were lost
+    CanBusOff = 2,    // controller entered bus-off
+    CanTxDrop = 3,    // frame not queued, or queued but never acknowledged
+    StuckChannel = 4, // current channel declared frozen, see CURRENT_FROZEN_WINDOWS
+}
+
+impl Counter {
+    pub const COUNT: usize = 5;
+    pub const ALL: [Counter; Counter::COUNT] =
+        [Counter::PecError, Counter::CanRxOverrun, Counter::CanBusOff, Counter::CanTxDrop, Counter::StuckChannel];
+}
+
+pub struct DiagnosticCounters {
+    counts: [AtomicU32; Counter::COUNT],
+}
+
+impl DiagnosticCounters {
+    const fn new() -> Self {
+        DiagnosticCounters { counts: [const { AtomicU32::new(0) }; Counter::COUNT] }
+    }
+
+    // Saturates instead of wrapping, a maxed out counter still reads as "a lot"
+    pub fn bump(&self, counter: Counter) {
+        let _ = self.counts[counter as usize]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| count.checked_add(1));
+    }
+
+    // Every counter in Counter order
+    pub fn snapshot(&self) -> [u32; Counter::COUNT] {
+        Counter::ALL.map(|counter| self.counts[counter as usize].load(Ordering::Relaxed))
+    }
+
+    // Returns the counts it clears, each counter is swapped on its own so no event is lost
+    // between the read and the clear
+    pub fn clear(&self) -> [u32; Counter::COUNT] {
+        Counter::ALL.map(|counter| self.counts[counter as usize].swap(0, Ordering::Relaxed))
+    }
+}
+
+pub static DIAGNOSTICS: DiagnosticCounters = DiagnosticCounters::new();
@@ -0,0 +1,111 @@
+// Fault classes raised by ltc_function and what each one does, the table itself is
+// config::FAULT_RESPONSES so the reaction to every fault can be reviewed in one place
+
+// Bits of the fault field in FAULTS and in the ErrorId frame
+pub const FAULT_TEMP: u16 = 1 << 0;
+pub const FAULT_VOLT: u16 = 1 << 1;
+pub const FAULT_FLAGS: u16 = 1 << 2;
+pub const FAULT_GRADIENT: u16 = 1 << 3;
+pub const FAULT_SUM: u16 = 1 << 4;
+pub const FAULT_READ: u16 = 1 << 5;
+pub const FAULT_CURRENT: u16 = 1 << 6;
+pub const FAULT_COMM: u16 = 1 << 7;
+pub const FAULT_STALE: u16 = 1 << 8;
+pub const FAULT_PACK: u16 = 1 << 9;
+pub const FAULT_CURRENT_ADC: u16 = 1 << 10;
+pub const FAULT_PACK_UV: u16 = 1 << 11;
+pub const FAULT_SPI_CS: u16 = 1 << 12;
+pub const FAULT_ALL: u16 = (1 << 13) - 1;
+
+// What the VCU is expected to do about the fault, highest active one goes out in ErrorId byte 4
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum Severity {
+    Warning = 1,  // report only
+    Derate = 2,   // the measurement is doubtful, limit the power
+    Critical = 3, // a cell is at risk
+}
+
+// Board LED lit while the fault is active. The debug LED blinks on any fault regardless
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FaultLed {
+    None,
+    Voltage,
+    Temperature,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct FaultResponse {
+    pub fault: u16,      // one FAULT_* bit
+    pub latch: bool,     // held until power cycle once raised, ResetLtc doesn't clear it
+    pub open_loop: bool, // err_check goes to the fault level
+    pub severity: Severity,
+    pub led: FaultLed,
+}
+
+// Faults that open the loop. A bit missing from the table opens it too, fail safe
+pub const fn open_loop_mask(table: &[FaultResponse]) -> u16 {
+    let mut mask = u16::MAX;
+    let mut i = 0;
+    while i < table.len() {
+        if !table[i].open_loop {
+            mask &= !table[i].fault;
+        }
+        i += 1;
+    }
+    mask
+}
+
+pub const fn latch_mask(table: &[FaultResponse]) -> u16 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < table.len() {
+        if table[i].latch {
+            mask |= table[i].fault;
+        }
+        i += 1;
+    }
+    mask
+}
+
+pub const fn led_mask(table: &[FaultResponse], led: FaultLed) -> u16 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < table.len() {
+        if table[i].led as u8 == led as u8 {
+            mask |= table[i].fault;
+        }
+        i += 1;
+    }
+    mask
+}
+
+// Every fault bit has exactly one entry, checked at compile time next to the table
+pub const fn covers_all(table: &[FaultResponse]) -> bool {
+    let mut seen = 0u16;
+    let mut i = 0;
+    while i < table.len() {
+        if table[i].fault.count_ones() != 1 || seen & table[i].fault != 0 {
+            return false;
+        }
+        seen |= table[i].fault;
+        i += 1;
+    }
+    seen == FAULT_ALL
+}
+
+// Highest severity among `faults`, None when clear. Unlisted bits count as Critical
+pub fn max_severity(table: &[FaultResponse], faults: u16) -> Option<Severity> {
+    if faults == 0 {
+        return None;
+    }
+    let unlisted = faults & !table.iter().fold(0, |mask, response| mask | response.fault);
+    if unlisted != 0 {
+        return Some(Severity::Critical);
+    }
+    table
+        .iter()
+        .filter(|response| faults & response.fault != 0)
+        .map(|response| response.severity)
+        .max()
+}
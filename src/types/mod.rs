@@ -1,48 +1,91 @@
 pub mod bms;
+pub mod units;
+pub mod scaling;
+pub mod fault;
+pub mod diagnostics;
 pub use bms::SLAVEBMS;
+pub use units::{DeciCelsius, DeciMilliAmp, DeciMilliVolt};
+pub use crate::config::IMPLAUSIBLE_VOLTAGE;
+use crate::config::{RX_COMMANDS, MAX_CELL_TEMP, MAX_CELL_VOLTAGE, MIN_CELL_TEMP, MIN_CELL_VOLTAGE};
 
 #[repr(u16)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum CanMsg {
     VoltageId = 0x54,
     TemperatureId = 0x55,
+    SelfTestResult = 0x56,
+    BalanceStatus = 0x57,
+    AvgTemperatureId = 0x58,
+    UpdateTiming = 0x59,
+    PackVoltage = 0x5A,
+    CellHistory = 0x5B,
+    PackSummary = 0x5C,
+    Thresholds = 0x5D,
+    ThermistorDetail = 0x5E,
+    DiagnosticCounters = 0x5F,
     Balancing = 0x1A4,
+    RunSelfTest = 0x1A5,
+    PrepareShutdown = 0x1A6,
+    ManualDischarge = 0x1A7,
+    ResetLtc = 0x1A8,
+    RequestCellHistory = 0x1A9,
+    SetPackMinVoltage = 0x1AA,
+    #[cfg(feature = "fault-injection")]
+    InjectFault = 0x1AB,
+    ForceBalance = 0x1AC,
+    GetThresholds = 0x1AD,
+    SetTopology = 0x1AE,
+    GetDiagnostics = 0x1AF,
     ErrorId = 0x14,
     Tech = 0x365,
     Tech1 = 0x366,
     Tech2 = 0x367,
     Tech3 = 0x368,
-    Tech4 = 0x369
+    Tech4 = 0x369,
+    TechDelta = 0x36A
 }
 
 impl CanMsg {
     pub fn as_raw(&self) -> u16 {
         *self as u16
     }
+
+    // Command carried by a received frame, None for IDs not in RX_COMMANDS
+    pub fn from_rx_id(id: u16) -> Option<CanMsg> {
+        RX_COMMANDS.iter().copied().find(|msg| msg.as_raw() == id)
+    }
 }
 
 #[repr(u16)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum VOLTAGES {
-    MAXVOLTAGE = 42000,
-    MINVOLTAGE = 30000
+    MAXVOLTAGE = MAX_CELL_VOLTAGE,
+    MINVOLTAGE = MIN_CELL_VOLTAGE
 }
 
 impl VOLTAGES {
     pub fn as_raw(&self) -> u16 {
         *self as u16
     }
+
+    pub fn value(&self) -> DeciMilliVolt {
+        DeciMilliVolt::new(self.as_raw())
+    }
 }
 
 #[repr(u16)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum TEMPERATURES {
-    MAXTEMP = 65000,
-    MINTEMP = 0
+    MAXTEMP = MAX_CELL_TEMP,
+    MINTEMP = MIN_CELL_TEMP
 }
 
 impl TEMPERATURES {
     pub fn _as_raw(&self) -> u16 {
         *self as u16
     }
+
+    pub fn value(&self) -> DeciCelsius {
+        DeciCelsius::new(self._as_raw())
+    }
 }
\ No newline at end of file
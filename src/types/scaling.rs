@@ -0,0 +1,58 @@
+// Every conversion between raw readings, the stored units and the CAN units, one function per
+// quantity so logs, CAN frames and fault checks can't disagree. Stored units:
+// cells DeciMilliVolt (0.1 mV), pack u32 in the same 100 uV, temps DeciCelsius (0.1 °C),
+// current DeciMilliAmp (100 uA, + = discharge). On CAN: cells and temps as stored, pack voltages
+// in 10 mV, current in 0.1 A. Logs round to mV/°C with DeciMilliVolt::millivolts/DeciCelsius::celsius.
+// LTC codes are already 100 uV, thermistor codes go through LTC6811::parse_temp
+use libm::roundf;
+use crate::config::{
+    ADC_FULL_SCALE, ADC_VREF_MV, CURRENT_DEADBAND, CURRENT_SCALE, CURRENT_SENSOR_MV_PER_A, CURRENT_SIGN, PACK_DIVIDER_RATIO
+};
+use super::units::{DeciMilliAmp, DeciMilliVolt};
+
+// Average of `samples` ADC conversions adding up to `sum`, as mV at the pin
+pub fn adc_to_mv(sum: u64, samples: u32) -> f32 {
+    (sum as f32 / samples as f32) * ADC_VREF_MV / ADC_FULL_SCALE
+}
+
+// Current sensor output (mV) to current. `zero_mv` is the auto-zeroed 0 A output and `ratio` its
+// share of VOLTAGE_OFFSET, the sensitivity follows the sensor supply like the offset does.
+// Anything within CURRENT_DEADBAND reads exactly 0
+pub fn current_from_sensor_mv(sensor_mv: f32, zero_mv: f32, ratio: f32) -> DeciMilliAmp {
+    let current = CURRENT_SIGN * ((sensor_mv - zero_mv) / (CURRENT_SENSOR_MV_PER_A * ratio)) * CURRENT_SCALE;
+    if current.abs() < CURRENT_DEADBAND {
+        DeciMilliAmp::new(0)
+    } else {
+        DeciMilliAmp::new(roundf(current) as i32)
+    }
+}
+
+// Pack sense pin (mV) through the divider to the 100 uV of tot_volt
+pub fn pack_from_pin_mv(pin_mv: f32) -> u32 {
+    roundf(pin_mv * PACK_DIVIDER_RATIO * 10f32).max(0.0) as u32
+}
+
+// Pack voltage (100 uV) to whole mV, rounded. A u32 holds any pack, no saturation needed
+pub fn pack_to_mv(pack: u32) -> u32 {
+    pack / 10 + (pack % 10 >= 5) as u32
+}
+
+// Pack voltage (100 uV) to the 10 mV of the CAN frames, truncated, saturating at u16::MAX
+pub fn pack_to_can(pack: u32) -> u16 {
+    (pack / 100).min(u16::MAX as u32) as u16
+}
+
+// Difference a - b of two pack voltages (100 uV) in 10 mV, truncated toward zero, saturating to i16
+pub fn pack_diff_to_can(a: u32, b: u32) -> i16 {
+    ((a as i64 - b as i64) / 100).clamp(i16::MIN as i64, i16::MAX as i64) as i16
+}
+
+// Cell spread to 10 mV, truncated, saturating at u8::MAX
+pub fn cell_delta_to_can(delta: DeciMilliVolt) -> u8 {
+    (delta.into_raw() / 100).min(u8::MAX as u16) as u8
+}
+
+// Current to the 0.1 A of the CAN frames, truncated toward zero, saturating to i16
+pub fn current_to_can(current: DeciMilliAmp) -> i16 {
+    (current.into_raw() / 1000).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
@@ -0,0 +1,70 @@
+// Fixed-point quantities in the units the firmware stores them, so a cell voltage can't be
+// handed to something expecting a temperature or a current by accident. The wrappers only carry
+// the unit: comparisons work on them directly, arithmetic on the raw value taken out with into_raw()
+macro_rules! unit {
+    ($(#[$doc:meta])* $name:ident($raw:ty)) => {
+        $(#[$doc])*
+        #[repr(transparent)]
+        #[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+        pub struct $name($raw);
+
+        impl $name {
+            pub const fn new(raw: $raw) -> Self {
+                $name(raw)
+            }
+
+            pub const fn into_raw(self) -> $raw {
+                self.0
+            }
+        }
+
+        impl From<$raw> for $name {
+            fn from(raw: $raw) -> Self {
+                $name(raw)
+            }
+        }
+    };
+}
+
+unit!(
+    /// Cell voltage in 0.1 mV (100 uV), the LTC6811 code unit
+    DeciMilliVolt(u16)
+);
+
+unit!(
+    /// Temperature in 0.1 °C, u16::MAX marks an open or shorted thermistor
+    DeciCelsius(u16)
+);
+
+unit!(
+    /// Current in 100 uA steps (1 A = 10000), positive = discharge, negative = charge/regen
+    DeciMilliAmp(i32)
+);
+
+impl DeciMilliVolt {
+    pub fn abs_diff(self, other: Self) -> Self {
+        DeciMilliVolt(self.0.abs_diff(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Self) -> Self {
+        DeciMilliVolt(self.0.saturating_sub(other.0))
+    }
+
+    // Rounded to whole mV, for logs
+    pub fn millivolts(self) -> u16 {
+        ((self.0 as u32 + 5) / 10) as u16
+    }
+}
+
+impl DeciCelsius {
+    // Rounded to whole °C, for logs
+    pub fn celsius(self) -> u16 {
+        ((self.0 as u32 + 5) / 10) as u16
+    }
+}
+
+impl DeciMilliAmp {
+    pub fn abs(self) -> Self {
+        DeciMilliAmp(self.0.saturating_abs())
+    }
+}
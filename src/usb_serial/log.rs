@@ -12,10 +12,65 @@ pub struct UsbDefmt;
 /// The `Encoder` holds the defmt wire‐format state machine.
 static mut ENCODER: Encoder = Encoder::new();
 
+/// Serial::write drops bytes when its queue is full, so the stream carries sync markers
+/// for the host to check it. After a defmt frame, once at least `SYNC_INTERVAL_BYTES` went
+/// through `do_write` since the last marker, this goes out (15 bytes):
+///
+/// `0xFF 'S' 'Y' 'N' 'C'` | sequence, 2 hex digits | CRC, 8 hex digits | `0x00`
+///
+/// The CRC is CRC-32/ISO-HDLC (the zlib one) over every byte `do_write` handed to Serial
+/// since the previous marker, the marker itself excluded. The sequence counts markers and
+/// wraps at 0xFF. Hex keeps 0x00 out of the marker, so like a defmt frame it ends at the
+/// first 0x00: the host splits the stream on 0x00, takes out the frames starting with
+/// 0xFF "SYNC" and checks the bytes in between. A wrong CRC or a skipped sequence means
+/// bytes were lost in that segment only, decoding picks up again at the next frame.
+const SYNC_INTERVAL_BYTES: u32 = 1024;
+
+struct LogSync {
+    crc: u32,   // CRC-32 register, not yet inverted
+    bytes: u32, // bytes since the last marker
+    seq: u8,
+}
+
+static mut LOG_SYNC: LogSync = LogSync { crc: 0xFFFF_FFFF, bytes: 0, seq: 0 };
+
 fn do_write(bytes: &[u8]) {
+    let ptr = &raw mut LOG_SYNC;
+    let sync = unsafe { &mut *ptr };
+    for &byte in bytes {
+        sync.crc ^= byte as u32;
+        for _ in 0..8 {
+            sync.crc = if sync.crc & 1 != 0 { (sync.crc >> 1) ^ 0xEDB8_8320 } else { sync.crc >> 1 };
+        }
+    }
+    sync.bytes = sync.bytes.wrapping_add(bytes.len() as u32);
     Serial::write(bytes);
 }
 
+// Only between frames, a marker inside one would break it for the decoder
+fn write_sync_marker() {
+    let ptr = &raw mut LOG_SYNC;
+    let sync = unsafe { &mut *ptr };
+    if sync.bytes < SYNC_INTERVAL_BYTES {
+        return;
+    }
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let crc = sync.crc ^ 0xFFFF_FFFF;
+    let mut marker = [0u8; 15];
+    marker[..5].copy_from_slice(&[0xFF, b'S', b'Y', b'N', b'C']);
+    marker[5] = HEX[(sync.seq >> 4) as usize];
+    marker[6] = HEX[(sync.seq & 0x0F) as usize];
+    for i in 0..8 {
+        marker[7 + i] = HEX[((crc >> (28 - 4 * i)) & 0x0F) as usize];
+    }
+    // marker[14] stays 0x00, the delimiter
+    Serial::write(&marker);
+
+    sync.crc = 0xFFFF_FFFF;
+    sync.bytes = 0;
+    sync.seq = sync.seq.wrapping_add(1);
+}
+
 /// Implement the new defmt::Logger trait
 /// — see https://defmt.ferrous-systems.com/global-logger
 unsafe impl Logger for UsbDefmt {
@@ -29,6 +84,7 @@ unsafe impl Logger for UsbDefmt {
 
     unsafe fn release() {
         (&mut *(&raw mut ENCODER)).end_frame(do_write);
+        write_sync_marker();
     }
 
     unsafe fn flush() {
@@ -41,6 +97,9 @@ unsafe impl Logger for UsbDefmt {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // 0) Fail safe before anything that could hang, see force_safe_state
+    crate::force_safe_state();
+
     // 1) Format the panic message
     let mut buf = heapless::String::<256>::new();
     if let Some(location) = info.location() {
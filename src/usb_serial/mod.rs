@@ -1,4 +1,6 @@
 pub mod usb;
+// defmt global logger and panic handler over USB, replaced by defmt-rtt/panic-probe with rtt-log
+#[cfg(not(feature = "rtt-log"))]
 pub mod log;
 
 use embassy_stm32::Config;